@@ -0,0 +1,186 @@
+use crate::encoding::{Encode, Decode, Reader, DecodeError};
+use std::fmt;
+use std::str::FromStr;
+
+/// Number of decimal places a coin can be divided into
+pub const DECIMALS: u32 = 8;
+
+/// Number of base units in one coin
+pub const COIN: u64 = 100_000_000;
+
+/// Upper bound for any single amount, in base units
+pub const MAX_MONEY: u64 = 21_000_000 * COIN;
+
+/// A non-negative quantity of coins, stored as an integer number of base units
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Amount(u64);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ParseAmountError {
+    Empty,
+    Negative,
+    InvalidCharacter(char),
+    TooPrecise,
+    TooLarge,
+}
+
+impl fmt::Display for ParseAmountError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ParseAmountError::Empty => write!(f, "empty amount"),
+            ParseAmountError::Negative => write!(f, "amounts cannot be negative"),
+            ParseAmountError::InvalidCharacter(c) => write!(f, "invalid character {:?} in amount", c),
+            ParseAmountError::TooPrecise => write!(f, "amounts have at most {} decimal places", DECIMALS),
+            ParseAmountError::TooLarge => write!(f, "amount exceeds the maximum of {} coins", MAX_MONEY / COIN),
+        }
+    }
+}
+
+impl std::error::Error for ParseAmountError {}
+
+impl Amount {
+    pub const ZERO: Amount = Amount(0);
+
+    pub const fn from_base_units(units: u64) -> Self {
+        Amount(units)
+    }
+
+    /// Returns `None` if the result would not fit into a `u64`
+    pub fn from_coins(coins: u64) -> Option<Self> {
+        coins.checked_mul(COIN).map(Amount)
+    }
+
+    pub fn base_units(self) -> u64 {
+        self.0
+    }
+
+    pub fn checked_add(self, other: Amount) -> Option<Amount> {
+        self.0.checked_add(other.0).map(Amount)
+    }
+
+    pub fn checked_sub(self, other: Amount) -> Option<Amount> {
+        self.0.checked_sub(other.0).map(Amount)
+    }
+
+    /// Whether the amount lies within the range of valid money
+    pub fn is_valid(self) -> bool {
+        self.0 <= MAX_MONEY
+    }
+}
+
+/// Formats the amount in coins, e.g. `1.5` for 150000000 base units
+impl fmt::Display for Amount {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let whole = self.0 / COIN;
+        let fraction = self.0 % COIN;
+        if fraction == 0 {
+            write!(f, "{}", whole)
+        }
+        else {
+            let digits = format!("{:0width$}", fraction, width = DECIMALS as usize);
+            write!(f, "{}.{}", whole, digits.trim_end_matches('0'))
+        }
+    }
+}
+
+/// Parses decimal coin notation, e.g. `"0.1"` or `"21"`
+impl FromStr for Amount {
+    type Err = ParseAmountError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.starts_with('-') {
+            return Err(ParseAmountError::Negative);
+        }
+        let (whole, fraction) = match s.find('.') {
+            Some(ix) => (&s[..ix], &s[ix + 1..]),
+            None => (s, ""),
+        };
+        if whole.is_empty() && fraction.is_empty() {
+            return Err(ParseAmountError::Empty);
+        }
+        if let Some(c) = whole.chars().chain(fraction.chars()).find(|c| !c.is_ascii_digit()) {
+            return Err(ParseAmountError::InvalidCharacter(c));
+        }
+        if fraction.len() > DECIMALS as usize {
+            return Err(ParseAmountError::TooPrecise);
+        }
+
+        let mut units: u64 = 0;
+        let padded = fraction.chars().chain(std::iter::repeat('0')).take(DECIMALS as usize);
+        for c in whole.chars().chain(padded) {
+            let digit = c.to_digit(10).unwrap() as u64;
+            units = units
+                .checked_mul(10)
+                .and_then(|u| u.checked_add(digit))
+                .ok_or(ParseAmountError::TooLarge)?;
+        }
+        let amount = Amount(units);
+        if !amount.is_valid() {
+            return Err(ParseAmountError::TooLarge);
+        }
+        Ok(amount)
+    }
+}
+
+impl Encode for Amount {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        self.0.encode(buf);
+    }
+}
+
+impl Decode for Amount {
+    fn decode(reader: &mut Reader) -> Result<Self, DecodeError> {
+        let amount = Amount(u64::decode(reader)?);
+        if !amount.is_valid() {
+            return Err(DecodeError::InvalidValue("amount"));
+        }
+        Ok(amount)
+    }
+}
+
+#[cfg(test)]
+mod test{
+    // Imports
+    use super::*;
+
+    #[test]
+    fn parse_and_display() {
+        let cases = [
+            ("1", 100_000_000, "1"),
+            ("1.5", 150_000_000, "1.5"),
+            ("0.1", 10_000_000, "0.1"),
+            (".25", 25_000_000, "0.25"),
+            ("0.00000001", 1, "0.00000001"),
+            ("3.", 300_000_000, "3"),
+        ];
+        for (input, units, output) in cases.iter() {
+            let amount: Amount = input.parse().unwrap();
+            assert_eq!(amount.base_units(), *units);
+            assert_eq!(amount.to_string(), *output);
+        }
+    }
+
+    #[test]
+    fn reject_malformed() {
+        assert_eq!("".parse::<Amount>(), Err(ParseAmountError::Empty));
+        assert_eq!(".".parse::<Amount>(), Err(ParseAmountError::Empty));
+        assert_eq!("-1".parse::<Amount>(), Err(ParseAmountError::Negative));
+        assert_eq!("1e5".parse::<Amount>(), Err(ParseAmountError::InvalidCharacter('e')));
+        assert_eq!("NaN".parse::<Amount>(), Err(ParseAmountError::InvalidCharacter('N')));
+        assert_eq!("1.2.3".parse::<Amount>(), Err(ParseAmountError::InvalidCharacter('.')));
+        assert_eq!("0.000000001".parse::<Amount>(), Err(ParseAmountError::TooPrecise));
+        assert_eq!("21000000.00000001".parse::<Amount>(), Err(ParseAmountError::TooLarge));
+        assert_eq!("99999999999999999999".parse::<Amount>(), Err(ParseAmountError::TooLarge));
+    }
+
+    #[test]
+    fn checked_arithmetic() {
+        let one = Amount::from_coins(1).unwrap();
+        let two = Amount::from_coins(2).unwrap();
+        assert_eq!(one.checked_add(one), Some(two));
+        assert_eq!(two.checked_sub(one), Some(one));
+        assert_eq!(one.checked_sub(two), None);
+        assert_eq!(Amount::from_base_units(u64::MAX).checked_add(one), None);
+        assert!(!Amount::from_base_units(u64::MAX).is_valid());
+    }
+}
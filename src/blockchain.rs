@@ -1,77 +1,1077 @@
-use crate::transaction::SignedTransaction;
-use crate::merkletree::MerkleTree;
-use crate::utils::{get_unix_timestamp, sha256_digest};
-use std::hash::{Hash, Hasher};
+use crate::amount::{Amount, COIN};
+use crate::transaction::{SignedTransaction, TxError};
+use crate::merkletree::{MerkleTree, MerkleError};
+use crate::ledger::{Ledger, LedgerError, LedgerMode};
+use crate::utils::{sha256, sha256_digest, leading_zero_bits, get_unix_timestamp};
+use crate::genesis::GenesisConfig;
+use crate::orphans::OrphanPool;
+use crate::index::ChainIndex;
+use crate::storage::{BlockStore, StorageError};
+use crate::error::Error;
+use crate::consensus::{ConsensusEngine, ConsensusError};
+use rsa::RSAPublicKey;
+use log::{info, warn};
+use std::collections::{HashMap, HashSet};
+use std::convert::TryFrom;
+use std::path::Path;
+use std::sync::Arc;
+use std::fmt;
+use crate::encoding::{Encode, Decode, Reader, DecodeError, ENCODING_VERSION};
 
-#[derive(Debug, Clone, Hash)]
-pub struct Block{
-    pub id: String,
-    pub transactions: MerkleTree<SignedTransaction>, 
-    pub nonce: i32,
-    pub timestamp: u64,
+/// The part of a block that is hashed, mined and signed.
+/// It commits to the transactions through their Merkle root, so headers can be
+/// relayed and checked without the transactions.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BlockHeader{
+    /// Version of the block rules the block follows
+    pub version: u32,
     pub previous_hash: Vec<u8>,
+    /// Root hash of the Merkle tree of the transactions
+    pub merkle_root: Vec<u8>,
+    pub timestamp: u64,
+    /// Number of leading zero bits the hash of this block has to have (proof-of-work)
+    pub difficulty: u32,
+    pub nonce: u64,
+    /// Number of blocks before this one, the genesis block has height zero
+    pub height: u64,
+}
+
+#[derive(Debug, Clone)]
+pub struct Block{
+    pub header: BlockHeader,
+    pub transactions: MerkleTree<SignedTransaction>,
+    /// Signature of the header by the validator that created the block
+    /// (proof-of-authority and proof-of-stake), empty otherwise
+    pub seal: Vec<u8>,
+}
+
+/// Version of the block rules implemented by this crate
+pub const BLOCK_VERSION: u32 = 1;
+
+/// Prefix of the digest a validator signs, so a seal can't be reused for other data
+const SEAL_TAG: &[u8] = b"blockchain.rs/block";
+
+/// Coins created by every block, in addition to the fees it collects (50 coins)
+pub const DEFAULT_BLOCK_REWARD: Amount = Amount::from_base_units(50 * COIN);
+
+/// Highest possible difficulty, every bit of the hash has to be zero
+pub const MAX_DIFFICULTY: u32 = 256;
+
+/// Number of previous blocks whose median timestamp a new block has to exceed
+pub const MEDIAN_TIME_SPAN: usize = 11;
+
+/// Number of seconds a block timestamp may lie ahead of the local clock (two hours)
+pub const MAX_FUTURE_DRIFT: u64 = 2 * 60 * 60;
+
+/// A block that is known to the chain, either on the active chain or on a side branch
+#[derive(Debug, Clone)]
+struct TreeEntry{
+    block: Block,
+    /// Work of this block and all of its ancestors
+    total_work: u128,
 }
 
+/// What happened to a block passed to `Blockchain::add`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BlockStatus{
+    /// The block was appended to the active chain
+    Extended,
+    /// The block was stored on a branch that has less work than the active chain
+    SideBranch,
+    /// The block completed a branch with more work, which is now the active chain.
+    /// `depth` blocks of the previous active chain were rolled back.
+    Reorganized{ depth: usize },
+    /// The block was added before
+    AlreadyKnown,
+    /// The parent of the block is unknown, it is kept in the orphan pool until the parent arrives
+    Orphaned,
+    /// The parent of the block is unknown and the orphan pool has no room for it
+    Dropped,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BlockError{
+    /// The header carries a version whose rules are unknown
+    UnsupportedVersion(u32),
+    /// `previous_hash` is not the full hash of the parent block
+    PreviousHashMismatch,
+    /// The header is checked on its own, but its parent is unknown
+    UnknownParent,
+    /// The height isn't one more than the height of the parent
+    WrongHeight{ expected: u64, got: u64 },
+    /// The block breaks the rules of the consensus engine
+    Consensus(ConsensusError),
+    /// The timestamp is not greater than the median of the previous blocks
+    TimestampTooEarly{ median: u64, got: u64 },
+    /// The timestamp lies too far ahead of the local clock
+    TimestampTooFarAhead{ limit: u64, got: u64 },
+    /// The Merkle tree of the transactions is malformed
+    Merkle(MerkleError),
+    /// The header doesn't commit to the transactions of the block
+    MerkleRootMismatch,
+    /// The transaction at `index` is invalid on its own
+    InvalidTransaction{ index: usize, error: TxError },
+    /// The transactions of the block are rejected by the ledger
+    Ledger(LedgerError),
+    /// The block or one of its ancestors was found invalid before
+    KnownInvalid,
+    /// The parent is unknown and the block weighs less than the tip, so it isn't kept as an orphan
+    InsufficientWork{ minimum: u128, got: u128 },
+    /// The block couldn't be written to the block store.
+    /// The message holds the error of the OS and the directory of the store.
+    Storage{ kind: std::io::ErrorKind, message: String },
+}
+
+impl fmt::Display for BlockError{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BlockError::UnsupportedVersion(v) => write!(f, "unsupported block version {}", v),
+            BlockError::PreviousHashMismatch => write!(f, "previous hash doesn't match the parent block"),
+            BlockError::UnknownParent => write!(f, "parent block is unknown"),
+            BlockError::WrongHeight{ expected, got } => {
+                write!(f, "block has height {} but {} is expected", got, expected)
+            },
+            BlockError::Consensus(e) => write!(f, "{}", e),
+            BlockError::TimestampTooEarly{ median, got } => {
+                write!(f, "block timestamp {} is not after the median time {}", got, median)
+            },
+            BlockError::TimestampTooFarAhead{ limit, got } => {
+                write!(f, "block timestamp {} lies beyond {}", got, limit)
+            },
+            BlockError::Merkle(e) => write!(f, "{}", e),
+            BlockError::MerkleRootMismatch => write!(f, "merkle root doesn't match the transactions"),
+            BlockError::InvalidTransaction{ index, error } => write!(f, "transaction {}: {}", index, error),
+            BlockError::Ledger(e) => write!(f, "{}", e),
+            BlockError::KnownInvalid => write!(f, "block descends from an invalid block"),
+            BlockError::InsufficientWork{ minimum, got } => {
+                write!(f, "orphan block has weight {} but at least {} is needed", got, minimum)
+            },
+            BlockError::Storage{ message, .. } => write!(f, "failed to store block: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for BlockError {}
+
+impl From<LedgerError> for BlockError{
+    fn from(e: LedgerError) -> Self {
+        BlockError::Ledger(e)
+    }
+}
+
+impl From<ConsensusError> for BlockError{
+    fn from(e: ConsensusError) -> Self {
+        BlockError::Consensus(e)
+    }
+}
+
+impl From<MerkleError> for BlockError{
+    fn from(e: MerkleError) -> Self {
+        BlockError::Merkle(e)
+    }
+}
+
+/// All known blocks, organized as a tree rooted at the genesis block.
+/// The branch with the highest total weight according to the consensus engine is the active chain.
 #[derive(Debug)]
 pub struct Blockchain{
+    /// The active chain, from the genesis block to the tip
     pub blocks: Vec<Block>,
+    /// Every known block including side branches, keyed by hash
+    tree: HashMap<Vec<u8>, TreeEntry>,
+    /// The state after applying every block of the active chain
+    ledger: Ledger,
+    /// Blocks whose parent is not known yet
+    orphans: OrphanPool,
+    /// Hashes of blocks that were dropped from the tree because they or their ancestors are invalid
+    invalid: HashSet<Vec<u8>>,
+    /// Lookups into the active chain
+    index: ChainIndex,
+    block_reward: Amount,
+    consensus: Arc<dyn ConsensusEngine>,
+    /// Where blocks are persisted, if the chain was opened from disk
+    store: Option<BlockStore>,
+}
+
+impl Encode for BlockHeader{
+    fn encode(&self, buf: &mut Vec<u8>) {
+        ENCODING_VERSION.encode(buf);
+        self.version.encode(buf);
+        self.previous_hash.encode(buf);
+        self.merkle_root.encode(buf);
+        self.timestamp.encode(buf);
+        self.difficulty.encode(buf);
+        self.nonce.encode(buf);
+        self.height.encode(buf);
+    }
 }
 
-//impl Hash for Block{
-//    fn hash<H: Hasher>(&self, state: &mut H) {
-//            self.id.hash(state);
-//            self.nonce.hash(state);
-//            self.timestamp.hash(state);
-//            self.previous_hash.hash(state);
-//            self.transactions.get_root_hash().hash(state);
-//    }
-//}
+impl Decode for BlockHeader{
+    fn decode(reader: &mut Reader) -> Result<Self, DecodeError> {
+        reader.expect_version()?;
+        Ok(BlockHeader{
+            version: u32::decode(reader)?,
+            previous_hash: Vec::decode(reader)?,
+            merkle_root: Vec::decode(reader)?,
+            timestamp: u64::decode(reader)?,
+            difficulty: u32::decode(reader)?,
+            nonce: u64::decode(reader)?,
+            height: u64::decode(reader)?,
+        })
+    }
+}
+
+impl Encode for Block{
+    fn encode(&self, buf: &mut Vec<u8>) {
+        self.header.encode(buf);
+        self.transactions.encode(buf);
+        self.seal.encode(buf);
+    }
+}
+
+impl Decode for Block{
+    fn decode(reader: &mut Reader) -> Result<Self, DecodeError> {
+        Ok(Block{
+            header: BlockHeader::decode(reader)?,
+            transactions: MerkleTree::decode(reader)?,
+            seal: Vec::decode(reader)?,
+        })
+    }
+}
+
+impl Default for Blockchain{
+    fn default() -> Self {
+        Self::new(LedgerMode::default())
+    }
+}
 
 impl Blockchain{
-    pub fn new() -> Self{
-        // Create the genesis block
-        let gen = Block{
-            id: "Genesis".to_string(),
-            transactions: MerkleTree::new(),
-            nonce: 0,
-            timestamp: get_unix_timestamp(),
-            previous_hash: Vec::new(),
-        };
-        Blockchain{
-            blocks: vec![gen],
+    /// Create a new chain from the default genesis block, which has no allocations.
+    /// `mode` decides whether coins are tracked per account or as unspent outputs.
+    pub fn new(mode: LedgerMode) -> Self{
+        let config = GenesisConfig{ ledger_mode: mode, ..GenesisConfig::default() };
+        Self::from_genesis(&config).expect("Genesis block without allocations is always valid")
+    }
+
+    /// Create a new chain that consists of only the genesis block described by `config`,
+    /// using the consensus engine of the config
+    pub fn from_genesis(config: &GenesisConfig) -> Result<Self, LedgerError>{
+        Self::with_consensus(config, config.consensus())
+    }
+
+    /// Create a new chain from the genesis block described by `config`, whose blocks follow the rules of `consensus`
+    pub fn with_consensus(config: &GenesisConfig, consensus: Arc<dyn ConsensusEngine>) -> Result<Self, LedgerError>{
+        let gen = config.block();
+        let mut ledger = Ledger::new(config.ledger_mode);
+        ledger.apply_genesis(&gen)?;
+        let mut tree = HashMap::new();
+        tree.insert(gen.hash(), TreeEntry{ block: gen.clone(), total_work: consensus.weight(&gen) });
+        let blocks = vec![gen];
+        Ok(Blockchain{
+            index: ChainIndex::new(&blocks),
+            blocks,
+            tree,
+            ledger,
+            orphans: OrphanPool::default(),
+            invalid: HashSet::new(),
+            block_reward: config.block_reward,
+            consensus,
+            store: None,
+        })
+    }
+
+    /// Open the chain stored in the directory `dir`, or start a new one there from the genesis
+    /// block described by `config`. Stored blocks are replayed in the order they were added,
+    /// blocks that are rejected are skipped with a warning. Every block added afterwards is
+    /// written to the store once it is accepted, before the chain changes.
+    pub fn open<P: AsRef<Path>>(config: &GenesisConfig, dir: P) -> Result<Self, Error>{
+        let mut bc = Self::from_genesis(config)?;
+        let mut store = BlockStore::open(dir)?;
+        let blocks = store.blocks()?;
+        match blocks.first() {
+            Some(gen) if gen.hash() != bc.tip_hash() => return Err(StorageError::GenesisMismatch.into()),
+            Some(_) => {},
+            None => store.append(&bc.blocks[0]).map_err(StorageError::from)?,
         }
+        for b in blocks.into_iter().skip(1) {
+            if let Err(e) = bc.add(b) {
+                warn!("Skipped stored block: {}", e);
+            }
+        }
+        info!("Opened chain with {} stored blocks at height {}", store.len(), bc.blocks.len() - 1);
+        bc.store = Some(store);
+        Ok(bc)
     }
 
-    pub fn add(&mut self, b: Block) {
-        self.blocks.push(b);
+    pub fn consensus(&self) -> Arc<dyn ConsensusEngine> {
+        self.consensus.clone()
     }
 
-    pub fn is_valid(&self) -> bool {
-        let mut is_valid = true;
-        // By definition, the genesis block cannot be invalid
-        for ix in 1..self.blocks.len() {
-            let block = &self.blocks[ix];
-            if !block.is_valid() {
-                is_valid = false;
-                break;
+    /// Whether the owner of `key` may create the next block on top of the active chain
+    pub fn can_seal(&self, key: &RSAPublicKey) -> bool {
+        let chain: Vec<&Block> = self.blocks.iter().collect();
+        self.consensus.can_seal(&chain, &self.ledger, key)
+    }
+
+    /// Set the consensus fields of the header of a new block on top of the active chain, e.g. its difficulty
+    pub fn prepare(&self, header: &mut BlockHeader) {
+        let chain: Vec<&Block> = self.blocks.iter().collect();
+        self.consensus.prepare(&chain, header);
+    }
+
+    /// The smallest timestamp the next block may have
+    pub fn min_timestamp(&self) -> u64 {
+        let chain: Vec<&Block> = self.blocks.iter().collect();
+        median_time(&chain) + 1
+    }
+
+    /// The hash of the last block of the active chain
+    pub fn tip_hash(&self) -> Vec<u8> {
+        self.blocks[self.blocks.len() - 1].hash()
+    }
+
+    /// The total weight of the active chain, e.g. its cumulative work
+    pub fn total_work(&self) -> u128 {
+        self.tree[&self.tip_hash()].total_work
+    }
+
+    /// Whether the block is known, either on the active chain or on a side branch
+    pub fn contains(&self, hash: &[u8]) -> bool {
+        self.tree.contains_key(hash)
+    }
+
+    /// The block of the active chain at `height`
+    pub fn block_at(&self, height: u64) -> Option<&Block> {
+        self.blocks.get(usize::try_from(height).ok()?)
+    }
+
+    /// The block with hash `hash`, either on the active chain or on a side branch
+    pub fn block(&self, hash: &[u8]) -> Option<&Block> {
+        self.tree.get(hash).map(|entry| &entry.block)
+    }
+
+    /// The height of the block with hash `hash`, if it is part of the active chain
+    pub fn height_of(&self, hash: &[u8]) -> Option<u64> {
+        self.index.height(hash)
+    }
+
+    /// The block of the active chain that contains the transaction with hash `hash`,
+    /// together with the position of the transaction within the block
+    pub fn transaction(&self, hash: &[u8]) -> Option<(&Block, usize)> {
+        let location = self.index.transaction(hash)?;
+        Some((self.block_at(location.height)?, location.index))
+    }
+
+    /// Every transaction of the active chain that `key` sent or received coins with, oldest first
+    pub fn transactions_of(&self, key: &RSAPublicKey) -> Vec<&SignedTransaction> {
+        self.index
+            .transactions_of(key)
+            .iter()
+            .filter_map(|location| {
+                let block = self.block_at(location.height)?;
+                block.transactions.leaves().get(location.index).copied()
+            })
+            .collect()
+    }
+
+    /// The blocks from the genesis block up to and including the block with hash `tip`
+    fn branch(&self, tip: &[u8]) -> Vec<&Block> {
+        let mut branch = Vec::new();
+        let mut current = self.tree.get(tip);
+        while let Some(entry) = current {
+            branch.push(&entry.block);
+            current = self.tree.get(&entry.block.header.previous_hash);
+        }
+        branch.reverse();
+        branch
+    }
+
+    /// The blocks of the branch ending in `tip` that are not part of the active chain,
+    /// e.g. the blocks that were rolled back when the active chain ended in `tip`
+    pub fn off_chain(&self, tip: &[u8]) -> Vec<&Block> {
+        self.branch(tip)
+            .into_iter()
+            .filter(|b| self.height_of(&b.hash()).is_none())
+            .collect()
+    }
+
+    /// Blocks that arrived before their parent
+    pub fn orphans(&self) -> &OrphanPool {
+        &self.orphans
+    }
+
+    /// Replace the orphan pool, e.g. to change its limits
+    pub fn set_orphan_pool(&mut self, orphans: OrphanPool) {
+        self.orphans = orphans;
+    }
+
+    /// The subsidy a miner may pay to themself in the coinbase of a block.
+    /// It is a consensus rule, so it is fixed by the genesis config.
+    pub fn block_reward(&self) -> Amount {
+        self.block_reward
+    }
+
+    /// The height the next block will have, the genesis block has height zero
+    pub fn next_height(&self) -> u64 {
+        self.blocks.len() as u64
+    }
+
+    /// The account balances after applying every block of the chain
+    pub fn ledger(&self) -> &Ledger {
+        &self.ledger
+    }
+
+    /// Add a block to the tree.
+    /// If it extends the active chain, its transactions are applied to the ledger right away.
+    /// If it completes a side branch with more cumulative work than the active chain, the
+    /// ledger is rebuilt along that branch and it becomes the new active chain.
+    /// Blocks on lighter side branches are only stored.
+    ///
+    /// Blocks whose parent is unknown go to the orphan pool. Once a block is stored, orphans
+    /// that descend from it are added as well. Invalid orphans are dropped with a warning,
+    /// the returned status only describes `b` itself.
+    pub fn add(&mut self, b: Block) -> Result<BlockStatus, BlockError> {
+        let hash = b.hash();
+        let status = self.connect(b)?;
+        if let BlockStatus::Extended | BlockStatus::SideBranch | BlockStatus::Reorganized{ .. } = status {
+            self.connect_orphans(hash);
+        }
+        Ok(status)
+    }
+
+    /// Add every orphan that descends from the block with hash `parent`
+    fn connect_orphans(&mut self, parent: Vec<u8>) {
+        let mut parents = vec![parent];
+        while let Some(parent) = parents.pop() {
+            for child in self.orphans.take_children(&parent) {
+                let hash = child.hash();
+                match self.connect(child) {
+                    Ok(BlockStatus::AlreadyKnown) => {},
+                    Ok(status) => {
+                        info!("Connected orphan block: {:?}", status);
+                        parents.push(hash);
+                    },
+                    Err(e) => warn!("Dropped invalid orphan block: {}", e),
+                }
             }
-            //Check if this blocks prev hash matches the previous blocks hash!
-            let prev_block = &self.blocks[ix - 1];
-            if !sha256_digest(&prev_block)
-                .iter()
-                .zip(&block.previous_hash)
-                .all(|(a, b)| a == b) {
-                is_valid = false;
-                break
+        }
+    }
+
+    /// Add a single block to the tree, or to the orphan pool if its parent is unknown.
+    /// Checks that don't depend on the parent are done before a block becomes an orphan.
+    fn connect(&mut self, b: Block) -> Result<BlockStatus, BlockError> {
+        let hash = b.hash();
+        if self.tree.contains_key(&hash) {
+            return Ok(BlockStatus::AlreadyKnown);
+        }
+        b.verify()?;
+        self.consensus.verify_seal(&b)?;
+        if self.invalid.contains(&hash) || self.invalid.contains(&b.header.previous_hash) {
+            self.invalid.insert(hash);
+            return Err(BlockError::KnownInvalid);
+        }
+        let total_work = match self.tree.get(&b.header.previous_hash) {
+            Some(parent) => parent.total_work.saturating_add(self.consensus.weight(&b)),
+            None => {
+                // Orphans can't be checked against their ancestors yet, so they have to
+                // carry real work. Otherwise cheap blocks would crowd out real ones.
+                let minimum = self.consensus.weight(self.blocks.last().expect("The chain always has a genesis block"));
+                let got = self.consensus.weight(&b);
+                if got < minimum {
+                    return Err(BlockError::InsufficientWork{ minimum, got });
+                }
+                if self.orphans.contains_block(&b) {
+                    return Ok(BlockStatus::AlreadyKnown);
+                }
+                if self.orphans.insert(b, get_unix_timestamp()) {
+                    return Ok(BlockStatus::Orphaned);
+                }
+                return Ok(BlockStatus::Dropped);
+            },
+        };
+
+        self.check_header(&b.header, &self.branch(&b.header.previous_hash), get_unix_timestamp())?;
+
+        // Blocks are only stored once they are accepted, so the store never holds a block
+        // that would take the place of a valid one with the same hash.
+        if b.header.previous_hash == self.tip_hash() {
+            let chain: Vec<&Block> = self.blocks.iter().collect();
+            self.consensus.verify_context(&chain, &self.ledger, &b)?;
+            let mut ledger = self.ledger.clone();
+            ledger.apply_block(&b, self.block_reward)?;
+            self.persist(&b)?;
+            self.ledger = ledger;
+            self.tree.insert(hash, TreeEntry{ block: b.clone(), total_work });
+            self.index.push(&b);
+            self.blocks.push(b);
+            return Ok(BlockStatus::Extended);
+        }
+
+        if total_work <= self.total_work() {
+            self.persist(&b)?;
+            self.tree.insert(hash, TreeEntry{ block: b, total_work });
+            return Ok(BlockStatus::SideBranch);
+        }
+        self.tree.insert(hash.clone(), TreeEntry{ block: b, total_work });
+        let depth = self.reorganize(&hash)?;
+        Ok(BlockStatus::Reorganized{ depth })
+    }
+
+    /// Make the branch ending in `tip` the active chain.
+    /// The state is rolled back to the genesis block and every block of the new branch is
+    /// reapplied. On error, the active chain and the ledger are left unchanged. If the first
+    /// invalid block comes after the fork point, it is dropped together with its descendants,
+    /// otherwise only `tip` is removed again, so the active chain stays in the tree.
+    /// Returns the number of blocks that were removed from the active chain.
+    fn reorganize(&mut self, tip: &[u8]) -> Result<usize, BlockError> {
+        let branch = self.branch(tip);
+        // Number of blocks the old and the new chain have in common
+        let common = self.blocks
+            .iter()
+            .zip(branch.iter())
+            .take_while(|(a, b)| a.hash() == b.hash())
+            .count();
+
+        let mut ledger = Ledger::new(self.ledger.mode());
+        ledger.apply_genesis(branch[0])?;
+        for (ix, block) in branch.iter().enumerate().skip(1) {
+            let result = self.consensus
+                .verify_context(&branch[..ix], &ledger, block)
+                .map_err(BlockError::from)
+                .and_then(|_| Ok(ledger.apply_block(block, self.block_reward)?));
+            if let Err(e) = result {
+                if ix >= common {
+                    let hash = block.hash();
+                    self.invalidate(&hash);
+                }
+                else {
+                    warn!("Failed to replay block {} of the active chain: {}", ix, e);
+                    self.tree.remove(tip);
+                }
+                return Err(e);
             }
         }
-        is_valid
+
+        let depth = self.blocks.len() - common;
+        info!("Reorganizing the chain, rolling back {} blocks and applying {}", depth, branch.len() - common);
+
+        // The rest of the branch is already stored as a side branch, only the new tip is not
+        let blocks: Vec<Block> = branch.into_iter().cloned().collect();
+        if let Err(e) = self.persist(&blocks[blocks.len() - 1]) {
+            self.tree.remove(tip);
+            return Err(e);
+        }
+        self.blocks = blocks;
+        self.index = ChainIndex::new(&self.blocks);
+        self.ledger = ledger;
+        Ok(depth)
+    }
+
+    /// Append an accepted block to the block store, if the chain has one
+    fn persist(&mut self, b: &Block) -> Result<(), BlockError> {
+        if let Some(store) = &mut self.store {
+            store.append(b).map_err(|e| BlockError::Storage{
+                kind: e.kind(),
+                message: format!("{} in {}", e, store.dir().display()),
+            })?;
+        }
+        Ok(())
+    }
+
+    /// Drop the block with hash `hash` and all of its descendants from the tree.
+    /// Their hashes are kept, so they and any blocks on top of them are rejected right away.
+    fn invalidate(&mut self, hash: &[u8]) {
+        let mut pending = vec![hash.to_vec()];
+        while let Some(hash) = pending.pop() {
+            pending.extend(
+                self.tree
+                    .iter()
+                    .filter(|(_, entry)| entry.block.header.previous_hash == hash)
+                    .map(|(child, _)| child.clone()),
+            );
+            self.tree.remove(&hash);
+            self.invalid.insert(hash);
+        }
+        warn!("Dropped an invalid branch, {} blocks are known to be invalid", self.invalid.len());
+    }
+
+    /// Check a header against its ancestors, before its transactions are known.
+    /// With proof-of-work this includes the work, other engines need the whole block to check the seal.
+    pub fn verify_header(&self, header: &BlockHeader) -> Result<(), BlockError> {
+        if !self.tree.contains_key(&header.previous_hash) {
+            return Err(BlockError::UnknownParent);
+        }
+        self.check_header(header, &self.branch(&header.previous_hash), get_unix_timestamp())
+    }
+
+    /// Check the rules that only need the header and its ancestors: the version, the link to
+    /// the parent, the height, the timestamp and the consensus rules for headers.
+    fn check_header(&self, header: &BlockHeader, chain: &[&Block], now: u64) -> Result<(), BlockError> {
+        if header.version != BLOCK_VERSION {
+            return Err(BlockError::UnsupportedVersion(header.version));
+        }
+        let parent = chain[chain.len() - 1];
+        if header.previous_hash != parent.hash() {
+            return Err(BlockError::PreviousHashMismatch);
+        }
+        let expected = parent.header.height + 1;
+        if header.height != expected {
+            return Err(BlockError::WrongHeight{ expected, got: header.height });
+        }
+        self.consensus.verify_header(chain, header)?;
+        let median = median_time(chain);
+        if header.timestamp <= median {
+            return Err(BlockError::TimestampTooEarly{ median, got: header.timestamp });
+        }
+        let limit = now.saturating_add(MAX_FUTURE_DRIFT);
+        if header.timestamp > limit {
+            return Err(BlockError::TimestampTooFarAhead{ limit, got: header.timestamp });
+        }
+        Ok(())
+    }
+
+    /// Check every block of the active chain, as if it was added one by one
+    pub fn verify(&self) -> Result<(), BlockError> {
+        let mut ledger = Ledger::new(self.ledger.mode());
+        ledger.apply_genesis(&self.blocks[0])?;
+        let chain: Vec<&Block> = self.blocks.iter().collect();
+        let now = get_unix_timestamp();
+        // By definition, the genesis block cannot be invalid
+        for (ix, block) in chain.iter().enumerate().skip(1) {
+            block.verify()?;
+            self.consensus.verify_seal(block)?;
+            self.check_header(&block.header, &chain[..ix], now)?;
+            self.consensus.verify_context(&chain[..ix], &ledger, block)?;
+            ledger.apply_block(block, self.block_reward)?;
+        }
+        Ok(())
     }
 
 }
 
+/// The median timestamp of the last `MEDIAN_TIME_SPAN` blocks of `chain`
+fn median_time(chain: &[&Block]) -> u64 {
+    let start = chain.len().saturating_sub(MEDIAN_TIME_SPAN);
+    let mut timestamps: Vec<u64> = chain[start..].iter().map(|b| b.header.timestamp).collect();
+    timestamps.sort_unstable();
+    timestamps[timestamps.len() / 2]
+}
+
+impl BlockHeader {
+    /// A header following the rules of this crate, whose other fields are still empty
+    pub fn new(previous_hash: Vec<u8>, height: u64, timestamp: u64) -> Self {
+        BlockHeader{
+            version: BLOCK_VERSION,
+            previous_hash,
+            merkle_root: Vec::new(),
+            timestamp,
+            difficulty: 0,
+            nonce: 0,
+            height,
+        }
+    }
+
+    pub fn hash(&self) -> Vec<u8> {
+        sha256_digest(self)
+    }
+
+    /// Whether the hash of the header is below the target given by its difficulty
+    pub fn meets_difficulty(&self) -> bool {
+        leading_zero_bits(&self.hash()) >= self.difficulty
+    }
+
+    /// The expected number of hashes needed to find a header with this difficulty
+    pub fn work(&self) -> u128 {
+        1u128.checked_shl(self.difficulty).unwrap_or(u128::MAX)
+    }
+}
+
 impl Block {
-    pub fn is_valid(&self) -> bool {
-        self.transactions.root.is_valid()
+    /// An unsealed block whose header commits to `transactions`
+    pub fn new(mut header: BlockHeader, transactions: MerkleTree<SignedTransaction>) -> Self {
+        header.merkle_root = transactions.get_root_hash().cloned().unwrap_or_default();
+        Block{ header, transactions, seal: Vec::new() }
+    }
+
+    /// Check the rules that only depend on the block itself, apart from the consensus rules:
+    /// the Merkle tree is consistent and committed to by the header, and every transaction is signed
+    pub fn verify(&self) -> Result<(), BlockError> {
+        self.transactions.verify()?;
+        if *self.transactions.get_root_hash()? != self.header.merkle_root {
+            return Err(BlockError::MerkleRootMismatch);
+        }
+        for (index, st) in self.transactions.leaves().iter().enumerate() {
+            st.verify().map_err(|error| BlockError::InvalidTransaction{ index, error })?;
+        }
+        Ok(())
+    }
+
+    /// The hash of the header, which identifies the block
+    pub fn hash(&self) -> Vec<u8> {
+        self.header.hash()
+    }
+
+    /// The digest that gets signed by the validator sealing the block.
+    /// Commits to the header and through it to the transactions.
+    pub fn seal_hash(&self) -> Vec<u8> {
+        let mut bytes = SEAL_TAG.to_vec();
+        self.header.encode(&mut bytes);
+        sha256(&bytes)
+    }
+
+    /// The receiver of the coinbase, if the block has one
+    pub fn miner(&self) -> Option<&RSAPublicKey> {
+        self.transactions
+            .leaves()
+            .first()
+            .filter(|st| st.transaction.is_coinbase())
+            .map(|st| &st.transaction.receiver)
+    }
+}
+
+#[cfg(test)]
+mod test{
+    // Imports
+    use super::*;
+    use crate::trader::Trader;
+    use crate::transaction::Transaction;
+    use crate::genesis::GenesisConfig;
+    use crate::consensus::Retarget;
+    use crate::test_utils::{coins, mine, mine_at, mine_on, solve};
+
+    #[test]
+    fn reject_blocks_without_work() {
+        let miner = Trader::new();
+        let mut bc = Blockchain::default();
+        let b = mine(&bc, &miner, vec![]);
+        assert_eq!(b.verify(), Ok(()));
+        bc.add(b.clone()).unwrap();
+        assert_eq!(bc.verify(), Ok(()));
+
+        // Changing the content invalidates the proof-of-work
+        let mut forged = b.clone();
+        while forged.header.meets_difficulty() {
+            forged.header.nonce += 1;
+        }
+        assert_eq!(forged.verify(), Ok(()));
+        bc.blocks[1] = forged;
+        assert_eq!(bc.verify(), Err(BlockError::Consensus(ConsensusError::InvalidProofOfWork)));
+
+        // Lowering the difficulty doesn't help either
+        let mut forged = b;
+        forged.header.difficulty = 0;
+        assert_eq!(forged.verify(), Ok(()));
+        bc.blocks[1] = forged;
+        assert!(matches!(bc.verify(), Err(BlockError::Consensus(ConsensusError::WrongDifficulty{ got: 0, .. }))));
+    }
+
+    #[test]
+    fn retarget_chain() {
+        let miner = Trader::new();
+        let config = GenesisConfig{
+            difficulty: 4,
+            retarget: Retarget{ window: 3, block_time: 10, max_step: 1 },
+            ..GenesisConfig::default()
+        };
+        let mut bc = Blockchain::from_genesis(&config).unwrap();
+
+        // Blocks come every second, much faster than desired
+        for height in 1..=6 {
+            let b = mine_at(&bc, &miner, height);
+            let expected = if height < 6 { 4 } else { 5 };
+            assert_eq!(b.header.difficulty, expected);
+            bc.add(b).unwrap();
+        }
+        assert_eq!(bc.verify(), Ok(()));
+
+        // Blocks that don't carry the adjusted difficulty are invalid
+        let mut b = mine_at(&bc, &miner, 7);
+        b.header.difficulty = 4;
+        solve(&mut b);
+        let wrong = ConsensusError::WrongDifficulty{ expected: 5, got: 4 };
+        assert_eq!(bc.add(b.clone()), Err(BlockError::Consensus(wrong)));
+        bc.blocks.push(b);
+        assert!(bc.verify().is_err());
+    }
+
+    #[test]
+    fn reorganize_to_heavier_branch() {
+        let alice = Trader::new();
+        let bob = Trader::new();
+        let mut bc = Blockchain::default();
+        let gen = bc.blocks[0].clone();
+
+        // Alice extends the chain by one block
+        let a1 = mine(&bc, &alice, vec![]);
+        assert_eq!(bc.add(a1.clone()), Ok(BlockStatus::Extended));
+        assert_eq!(bc.add(a1.clone()), Ok(BlockStatus::AlreadyKnown));
+        assert_eq!(bc.ledger().balance(&alice.public_key), bc.block_reward());
+
+        // Bob builds a competing branch from the genesis block, with equal work at first
+        let b1 = mine_on(&gen, &bc, &bob, vec![], 1);
+        assert_eq!(bc.add(b1.clone()), Ok(BlockStatus::SideBranch));
+        assert_eq!(bc.ledger().balance(&bob.public_key), Amount::ZERO);
+
+        // With more work, the branch becomes the active chain
+        let b2 = mine_on(&b1, &bc, &bob, vec![], 2);
+        assert_eq!(bc.add(b2.clone()), Ok(BlockStatus::Reorganized{ depth: 1 }));
+        assert_eq!(bc.tip_hash(), b2.hash());
+        assert_eq!(bc.blocks.len(), 3);
+        assert_eq!(bc.verify(), Ok(()));
+
+        // Alices block is the only one that left the active chain
+        let rolled_back: Vec<Vec<u8>> = bc.off_chain(&a1.hash()).iter().map(|b| b.hash()).collect();
+        assert_eq!(rolled_back, vec![a1.hash()]);
+
+        // Alices coinbase is rolled back, bobs coinbases are applied
+        let reward = bc.block_reward();
+        assert_eq!(bc.ledger().balance(&alice.public_key), Amount::ZERO);
+        assert_eq!(bc.ledger().balance(&bob.public_key), reward.checked_add(reward).unwrap());
+
+        // Blocks whose parent is unknown wait in the orphan pool
+        let mut orphan = mine(&bc, &alice, vec![]);
+        orphan.header.previous_hash = vec![0; 32];
+        solve(&mut orphan);
+        assert_eq!(bc.add(orphan), Ok(BlockStatus::Orphaned));
+    }
+
+    #[test]
+    fn reject_invalid_heavier_branch() {
+        let alice = Trader::new();
+        let bob = Trader::new();
+        let mut bc = Blockchain::default();
+        let gen = bc.blocks[0].clone();
+        bc.add(mine(&bc, &alice, vec![])).unwrap();
+
+        // Bob's branch pays himself twice the reward, which only fails once it is applied
+        let mut b1 = mine_on(&gen, &bc, &bob, vec![], 1);
+        let greedy = Transaction::coinbase(bob.public_key.clone(), coins(100), 1);
+        let mut transactions = MerkleTree::new();
+        transactions.add(bob.sign(greedy));
+        b1 = Block::new(b1.header, transactions);
+        solve(&mut b1);
+        assert_eq!(bc.add(b1.clone()), Ok(BlockStatus::SideBranch));
+        let b2 = mine_on(&b1, &bc, &bob, vec![], 2);
+        assert!(matches!(bc.add(b2.clone()), Err(BlockError::Ledger(LedgerError::InvalidCoinbaseAmount{ .. }))));
+        assert_eq!(bc.blocks.len(), 2);
+        assert_eq!(bc.ledger().balance(&alice.public_key), bc.block_reward());
+
+        // The whole branch is dropped, it is not rebuilt for every new block on top of it
+        assert!(!bc.contains(&b1.hash()));
+        assert!(!bc.contains(&b2.hash()));
+        assert_eq!(bc.add(mine_on(&b2, &bc, &bob, vec![], 3)), Err(BlockError::KnownInvalid));
+        assert_eq!(bc.add(b1), Err(BlockError::KnownInvalid));
+    }
+
+    #[test]
+    fn keep_active_chain_on_failed_reorganization() {
+        let alice = Trader::new();
+        let bob = Trader::new();
+        let mut bc = Blockchain::default();
+        let a1 = mine(&bc, &alice, vec![]);
+        bc.add(a1.clone()).unwrap();
+        let a2 = mine(&bc, &alice, vec![]);
+        bc.add(a2.clone()).unwrap();
+
+        // The common prefix no longer passes, e.g. because the rules changed under the chain
+        bc.block_reward = coins(1);
+        let b2 = mine_on(&a1, &bc, &bob, vec![], 2);
+        assert_eq!(bc.add(b2.clone()), Ok(BlockStatus::SideBranch));
+        let b3 = mine_on(&b2, &bc, &bob, vec![], 3);
+        assert!(matches!(bc.add(b3.clone()), Err(BlockError::Ledger(LedgerError::InvalidCoinbaseAmount{ .. }))));
+
+        // Nothing of the active chain is dropped, only the new tip is gone again
+        assert_eq!(bc.tip_hash(), a2.hash());
+        assert!(bc.contains(&a1.hash()) && bc.contains(&a2.hash()) && bc.contains(&b2.hash()));
+        assert!(!bc.contains(&b3.hash()));
+        assert!(bc.total_work() > 0);
+        assert_eq!(bc.add(mine(&bc, &alice, vec![])), Ok(BlockStatus::Extended));
+    }
+
+    #[test]
+    fn connect_orphans() {
+        let miner = Trader::new();
+        let mut bc = Blockchain::default();
+        let mut other = Blockchain::default();
+        let mut blocks = Vec::new();
+        for height in 1..=3 {
+            let b = mine_at(&other, &miner, height);
+            other.add(b.clone()).unwrap();
+            blocks.push(b);
+        }
+
+        // The blocks arrive in reverse order
+        assert_eq!(bc.add(blocks[2].clone()), Ok(BlockStatus::Orphaned));
+        assert_eq!(bc.add(blocks[1].clone()), Ok(BlockStatus::Orphaned));
+        assert_eq!(bc.add(blocks[1].clone()), Ok(BlockStatus::AlreadyKnown));
+        assert_eq!(bc.orphans().len(), 2);
+        assert_eq!(bc.blocks.len(), 1);
+
+        // The missing parent connects both orphans
+        assert_eq!(bc.add(blocks[0].clone()), Ok(BlockStatus::Extended));
+        assert!(bc.orphans().is_empty());
+        assert_eq!(bc.orphans().stats().connected, 2);
+        assert_eq!(bc.tip_hash(), blocks[2].hash());
+        assert_eq!(bc.verify(), Ok(()));
+
+        // Orphans without work are rejected instead of filling the pool
+        let mut cheap = mine(&bc, &miner, vec![]);
+        cheap.header.previous_hash = vec![1; 32];
+        cheap.header.difficulty = 0;
+        assert!(matches!(bc.add(cheap), Err(BlockError::InsufficientWork{ got: 1, .. })));
+        assert!(bc.orphans().is_empty());
+
+        // Without room in the pool, orphans are dropped
+        bc.set_orphan_pool(OrphanPool::new(0, 10));
+        let mut orphan = mine(&bc, &miner, vec![]);
+        orphan.header.previous_hash = vec![1; 32];
+        solve(&mut orphan);
+        assert_eq!(bc.add(orphan), Ok(BlockStatus::Dropped));
+    }
+
+    #[test]
+    fn contextual_validation() {
+        let miner = Trader::new();
+        let mut bc = Blockchain::default();
+        for _ in 0..3 {
+            bc.add(mine(&bc, &miner, vec![])).unwrap();
+        }
+
+        // Not after the median of the previous timestamps (0, 1, 2, 3)
+        let b = mine_at(&bc, &miner, 2);
+        assert_eq!(bc.add(b), Err(BlockError::TimestampTooEarly{ median: 2, got: 2 }));
+
+        // Too far in the future
+        let future = get_unix_timestamp() + MAX_FUTURE_DRIFT + 60;
+        let b = mine_at(&bc, &miner, future);
+        assert!(matches!(bc.add(b), Err(BlockError::TimestampTooFarAhead{ .. })));
+
+        // A coinbase that was changed after signing
+        let mut b = mine(&bc, &miner, vec![]);
+        let mut coinbase = b.transactions.leaves()[0].clone();
+        coinbase.transaction.amount = coins(1);
+        let mut transactions = MerkleTree::new();
+        transactions.add(coinbase);
+        b = Block::new(b.header, transactions);
+        solve(&mut b);
+        assert_eq!(bc.add(b), Err(BlockError::InvalidTransaction{ index: 0, error: TxError::InvalidSignature }));
+
+        // Unsolved blocks are rejected before they can become orphans
+        let mut b = mine(&bc, &miner, vec![]);
+        b.header.previous_hash = vec![1; 32];
+        while b.header.meets_difficulty() {
+            b.header.nonce += 1;
+        }
+        assert_eq!(bc.add(b), Err(BlockError::Consensus(ConsensusError::InvalidProofOfWork)));
+        assert!(bc.orphans().is_empty());
+        assert_eq!(bc.blocks.len(), 4);
+
+        // A truncated previous hash doesn't link to the parent
+        let mut b = bc.blocks[3].clone();
+        b.header.previous_hash.truncate(1);
+        solve(&mut b);
+        bc.blocks[3] = b;
+        assert_eq!(bc.verify(), Err(BlockError::PreviousHashMismatch));
+    }
+
+    #[test]
+    fn header_commitment() {
+        let alice = Trader::new();
+        let bob = Trader::new();
+        let mut bc = Blockchain::default();
+        let b = mine(&bc, &alice, vec![]);
+
+        // Headers are checked without their transactions, including the work
+        assert_eq!(bc.verify_header(&b.header), Ok(()));
+        let mut header = b.header.clone();
+        while header.meets_difficulty() {
+            header.nonce += 1;
+        }
+        assert_eq!(bc.verify_header(&header), Err(BlockError::Consensus(ConsensusError::InvalidProofOfWork)));
+        let mut header = b.header.clone();
+        header.height = 5;
+        assert_eq!(bc.verify_header(&header), Err(BlockError::WrongHeight{ expected: 1, got: 5 }));
+        header.previous_hash = vec![0; 32];
+        assert_eq!(bc.verify_header(&header), Err(BlockError::UnknownParent));
+
+        // The header commits to the transactions, swapping them keeps the hash but breaks the commitment
+        let mut swapped = b.clone();
+        swapped.transactions = mine(&bc, &bob, vec![]).transactions;
+        assert_eq!(swapped.hash(), b.hash());
+        assert_eq!(bc.add(swapped), Err(BlockError::MerkleRootMismatch));
+
+        // Repeating the last transaction doesn't lead to the same root either
+        let mut duplicated = b.clone();
+        duplicated.transactions.add(b.transactions.leaves()[0].clone());
+        assert_eq!(duplicated.hash(), b.hash());
+        assert_eq!(bc.add(duplicated), Err(BlockError::MerkleRootMismatch));
+        assert_eq!(bc.add(b), Ok(BlockStatus::Extended));
+    }
+
+    #[test]
+    fn indexes() {
+        let alice = Trader::new();
+        let bob = Trader::new();
+        let config = GenesisConfig{
+            allocations: vec![(alice.public_key.clone(), coins(100))],
+            ..GenesisConfig::default()
+        };
+        let mut bc = Blockchain::from_genesis(&config).unwrap();
+        let gen = bc.blocks[0].clone();
+        let a1 = mine(&bc, &alice, vec![]);
+        bc.add(a1.clone()).unwrap();
+
+        assert_eq!(bc.block_at(1).map(Block::hash), Some(a1.hash()));
+        assert!(bc.block_at(2).is_none());
+        assert_eq!(bc.height_of(&a1.hash()), Some(1));
+        let coinbase = a1.transactions.leaves()[0].transaction.hash();
+        let (block, index) = bc.transaction(&coinbase).unwrap();
+        assert_eq!((block.hash(), index), (a1.hash(), 0));
+        // The genesis allocation and the coinbase
+        assert_eq!(bc.transactions_of(&alice.public_key).len(), 2);
+        assert!(bc.transactions_of(&bob.public_key).is_empty());
+
+        // After a reorganization, the blocks of the old chain are only known by hash
+        let b1 = mine_on(&gen, &bc, &bob, vec![], 1);
+        bc.add(b1.clone()).unwrap();
+        bc.add(mine_on(&b1, &bc, &bob, vec![], 2)).unwrap();
+        assert_eq!(bc.block_at(1).map(Block::hash), Some(b1.hash()));
+        assert!(bc.block(&a1.hash()).is_some());
+        assert_eq!(bc.height_of(&a1.hash()), None);
+        assert!(bc.transaction(&coinbase).is_none());
+        assert_eq!(bc.transactions_of(&alice.public_key).len(), 1);
+        let received: Vec<u64> = bc.transactions_of(&bob.public_key).iter().map(|st| st.transaction.nonce).collect();
+        assert_eq!(received, vec![1, 2]);
+    }
+
+    #[test]
+    fn allocations_are_not_coinbases() {
+        let alice = Trader::new();
+        let bob = Trader::new();
+        let reward = coins(50);
+        let config = GenesisConfig{
+            ledger_mode: LedgerMode::Utxo,
+            block_reward: reward,
+            allocations: vec![(bob.public_key.clone(), reward), (alice.public_key.clone(), reward)],
+            ..GenesisConfig::default()
+        };
+        let mut bc = Blockchain::from_genesis(&config).unwrap();
+        let gen = bc.blocks[0].clone();
+
+        // Alice mines at the height that equals the index of her allocation, for the same amount
+        let a1 = mine(&bc, &alice, vec![]);
+        bc.add(a1.clone()).unwrap();
+        assert_eq!(bc.ledger().balance(&alice.public_key), reward.checked_add(reward).unwrap());
+        let allocation = gen.transactions.leaves()[1].transaction.hash();
+        let coinbase = a1.transactions.leaves()[0].transaction.hash();
+        assert_ne!(allocation, coinbase);
+        assert_eq!(bc.transaction(&allocation).map(|(b, ix)| (b.hash(), ix)), Some((gen.hash(), 1)));
+
+        // Allocations are only valid in the genesis block, even when they are signed
+        let mut b = mine(&bc, &alice, vec![]);
+        b.transactions.add(alice.sign(Transaction::allocation(alice.public_key.clone(), reward, 2)));
+        b = Block::new(b.header, b.transactions);
+        solve(&mut b);
+        assert_eq!(bc.add(b), Err(BlockError::Ledger(LedgerError::UnexpectedAllocation)));
     }
 }
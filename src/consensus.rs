@@ -0,0 +1,460 @@
+use crate::blockchain::{Block, BlockHeader, MAX_DIFFICULTY};
+use crate::encoding::Encode;
+use crate::ledger::{Address, Ledger};
+use crate::miner::{Interrupt, MiningEngine};
+use crate::utils::sha256;
+use rsa::{RSAPrivateKey, RSAPublicKey, PaddingScheme, PublicKey, Hash};
+use std::fmt;
+
+/// Prefix of the digest that elects the leader of a slot
+const LEADER_TAG: &[u8] = b"blockchain.rs/leader";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConsensusError{
+    /// The block hash does not meet the difficulty of the block
+    InvalidProofOfWork,
+    /// The block is not mined with the difficulty expected at its position
+    WrongDifficulty{ expected: u32, got: u32 },
+    /// The block is not signed by any of the validators
+    InvalidSeal,
+    /// The block is signed by a validator, but it was another validator's turn
+    WrongValidator{ height: u64 },
+    /// The block is not signed by the leader elected for its slot
+    NotLeader{ height: u64 },
+}
+
+impl fmt::Display for ConsensusError{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConsensusError::InvalidProofOfWork => write!(f, "block hash doesn't meet its difficulty"),
+            ConsensusError::WrongDifficulty{ expected, got } => {
+                write!(f, "block has difficulty {} but {} is expected", got, expected)
+            },
+            ConsensusError::InvalidSeal => write!(f, "block isn't signed by a validator"),
+            ConsensusError::WrongValidator{ height } => {
+                write!(f, "block at height {} is signed by a validator out of turn", height)
+            },
+            ConsensusError::NotLeader{ height } => {
+                write!(f, "block at height {} isn't signed by the elected leader", height)
+            },
+        }
+    }
+}
+
+impl std::error::Error for ConsensusError {}
+
+/// What a miner hands to the consensus engine to seal a block
+pub struct SealContext<'a>{
+    /// The key of the miner, for engines that sign blocks
+    pub key: &'a RSAPrivateKey,
+    /// Regularly asked by engines that take a while, whether they should give up
+    pub check: &'a mut dyn FnMut() -> Option<Interrupt>,
+    /// Number of candidates the engine tried, e.g. hashes. Engines that only sign leave it at zero.
+    pub attempts: u64,
+}
+
+impl<'a> SealContext<'a>{
+    pub fn new(key: &'a RSAPrivateKey, check: &'a mut dyn FnMut() -> Option<Interrupt>) -> Self {
+        SealContext{ key, check, attempts: 0 }
+    }
+}
+
+/// The rules that decide who may create blocks and which branch of the block tree is the active chain.
+/// Everything else, like the ledger rules and timestamps, is the same for every engine.
+pub trait ConsensusEngine: Send + Sync + fmt::Debug {
+    /// Set the consensus fields of the header of a new block on top of `chain`, which starts at the genesis block
+    fn prepare(&self, chain: &[&Block], header: &mut BlockHeader);
+
+    /// Whether the owner of `key` may create the block following `chain`, `ledger` is the state after `chain`
+    fn can_seal(&self, chain: &[&Block], ledger: &Ledger, key: &RSAPublicKey) -> bool {
+        let _ = (chain, ledger, key);
+        true
+    }
+
+    /// Make a prepared block valid, e.g. by solving a puzzle or signing it with the key of the miner.
+    /// Only engines that take a while need `ctx.check`, signing never gets interrupted.
+    fn seal(&self, b: &mut Block, ctx: &mut SealContext) -> Result<(), Interrupt>;
+
+    /// Checks that only depend on the block itself.
+    /// They are done before a block whose parent is unknown is kept around.
+    fn verify_seal(&self, b: &Block) -> Result<(), ConsensusError>;
+
+    /// Checks of a header that depend on its ancestors, `chain` ends with its parent.
+    /// They are done before the transactions of the block are known.
+    fn verify_header(&self, chain: &[&Block], header: &BlockHeader) -> Result<(), ConsensusError> {
+        let _ = (chain, header);
+        Ok(())
+    }
+
+    /// Checks of the whole block that depend on its ancestors, `chain` ends with its parent and
+    /// `ledger` is the state after the parent. They are done right before the block is applied,
+    /// for blocks on a side branch that is once the branch becomes the active chain.
+    fn verify_context(&self, chain: &[&Block], ledger: &Ledger, b: &Block) -> Result<(), ConsensusError> {
+        let _ = (chain, ledger, b);
+        Ok(())
+    }
+
+    /// How much a block adds to its branch. The branch with the highest total weight is the active chain,
+    /// if every block counts the same that is the longest chain.
+    fn weight(&self, b: &Block) -> u128;
+}
+
+/// Parameters of the difficulty adjustment
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Retarget{
+    /// Number of blocks between two adjustments, zero disables retargeting
+    pub window: u64,
+    /// Desired number of seconds between two blocks
+    pub block_time: u64,
+    /// Maximum number of bits the difficulty may change by in a single adjustment
+    pub max_step: u32,
+}
+
+impl Default for Retarget{
+    fn default() -> Self {
+        Retarget{
+            window: 10,
+            block_time: 10,
+            max_step: 2,
+        }
+    }
+}
+
+impl Retarget{
+    /// The difficulty after a window of blocks that took `actual` seconds.
+    /// Every time the blocks came twice as fast as desired the difficulty rises by one bit,
+    /// every time they took twice as long it drops by one bit, up to `max_step` bits.
+    pub fn adjust(&self, difficulty: u32, actual: u64) -> u32 {
+        let expected = self.window as u128 * self.block_time as u128;
+        // Blocks may carry equal or even decreasing timestamps
+        let actual = actual.max(1) as u128;
+
+        // Largest `step` for which `fast << step <= slow`, without shifting out any bits
+        let max_step = self.max_step.min(127);
+        let bits = |fast: u128, slow: u128| {
+            let mut step = 0;
+            while step < max_step && fast <= slow >> (step + 1) {
+                step += 1;
+            }
+            step as i64
+        };
+        let step = if actual < expected {
+            bits(actual, expected)
+        }
+        else {
+            -bits(expected, actual)
+        };
+        (difficulty as i64 + step).clamp(0, MAX_DIFFICULTY as i64) as u32
+    }
+
+    /// The difficulty of the block following `chain`, which starts at the genesis block.
+    /// It is adjusted every `window` blocks, depending on how long the last window took.
+    /// The genesis block is never part of the measurement, since its timestamp is arbitrary.
+    pub fn next_difficulty(&self, chain: &[&Block]) -> u32 {
+        let height = chain.len();
+        let parent = &chain[height - 1].header;
+        let window = self.window as usize;
+        if window == 0 || !height.is_multiple_of(window) || height <= window + 1 {
+            return parent.difficulty;
+        }
+        let first = &chain[height - 1 - window].header;
+        let actual = parent.timestamp.saturating_sub(first.timestamp);
+        self.adjust(parent.difficulty, actual)
+    }
+}
+
+/// Blocks are created by finding a nonce for which the block hash has enough leading zero bits.
+/// The difficulty is retargeted so blocks are found at a steady rate.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ProofOfWork{
+    pub retarget: Retarget,
+    /// How this node searches nonces when it mines, doesn't affect which blocks are valid
+    pub search: MiningEngine,
+}
+
+impl ProofOfWork{
+    /// Proof-of-work that mines with one worker per CPU core
+    pub fn new(retarget: Retarget) -> Self {
+        ProofOfWork{ retarget, search: MiningEngine::default() }
+    }
+}
+
+impl ConsensusEngine for ProofOfWork{
+    fn prepare(&self, chain: &[&Block], header: &mut BlockHeader) {
+        header.difficulty = self.retarget.next_difficulty(chain);
+    }
+
+    fn seal(&self, b: &mut Block, ctx: &mut SealContext) -> Result<(), Interrupt> {
+        self.search.search(&mut b.header, &mut ctx.attempts, &mut *ctx.check)
+    }
+
+    fn verify_seal(&self, b: &Block) -> Result<(), ConsensusError> {
+        if !b.header.meets_difficulty() {
+            return Err(ConsensusError::InvalidProofOfWork);
+        }
+        Ok(())
+    }
+
+    fn verify_header(&self, chain: &[&Block], header: &BlockHeader) -> Result<(), ConsensusError> {
+        // Blocks have to do the expected amount of work, not just any amount
+        let expected = self.retarget.next_difficulty(chain);
+        if header.difficulty != expected {
+            return Err(ConsensusError::WrongDifficulty{ expected, got: header.difficulty });
+        }
+        if !header.meets_difficulty() {
+            return Err(ConsensusError::InvalidProofOfWork);
+        }
+        Ok(())
+    }
+
+    /// The most work wins
+    fn weight(&self, b: &Block) -> u128 {
+        b.header.work()
+    }
+}
+
+/// Blocks are created by a fixed set of validators that take turns, no work is needed.
+/// The validator at `height % validators.len()` signs the block at `height`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProofOfAuthority{
+    pub validators: Vec<RSAPublicKey>,
+}
+
+impl ProofOfAuthority{
+    pub fn new(validators: Vec<RSAPublicKey>) -> Self {
+        ProofOfAuthority{ validators }
+    }
+
+    /// The validator whose turn it is to seal the block at `height`
+    pub fn scheduled(&self, height: u64) -> Option<&RSAPublicKey> {
+        if self.validators.is_empty() {
+            return None;
+        }
+        self.validators.get((height % self.validators.len() as u64) as usize)
+    }
+}
+
+impl ConsensusEngine for ProofOfAuthority{
+    fn prepare(&self, _chain: &[&Block], header: &mut BlockHeader) {
+        header.difficulty = 0;
+    }
+
+    fn can_seal(&self, chain: &[&Block], _ledger: &Ledger, key: &RSAPublicKey) -> bool {
+        self.scheduled(chain.len() as u64) == Some(key)
+    }
+
+    fn seal(&self, b: &mut Block, ctx: &mut SealContext) -> Result<(), Interrupt> {
+        sign(b, ctx.key);
+        Ok(())
+    }
+
+    fn verify_seal(&self, b: &Block) -> Result<(), ConsensusError> {
+        if !self.validators.iter().any(|key| signed_by(b, key)) {
+            return Err(ConsensusError::InvalidSeal);
+        }
+        Ok(())
+    }
+
+    fn verify_context(&self, chain: &[&Block], _ledger: &Ledger, b: &Block) -> Result<(), ConsensusError> {
+        let height = chain.len() as u64;
+        match self.scheduled(height) {
+            Some(key) if signed_by(b, key) => Ok(()),
+            _ => Err(ConsensusError::WrongValidator{ height }),
+        }
+    }
+
+    fn weight(&self, _b: &Block) -> u128 {
+        1
+    }
+}
+
+/// Blocks are created by traders that locked coins with stake transactions.
+/// Every height is a slot with a single leader, drawn pseudo-randomly from the hash of the parent
+/// block with a chance proportional to the stake. The leader signs the block and receives the coinbase.
+///
+/// Since the parent hash decides the next leader, a leader can try different blocks to
+/// improve their chances for the following slot (stake grinding).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ProofOfStake;
+
+impl ProofOfStake{
+    /// The leader of the slot following `chain`, or none if nobody has any stake.
+    /// `ledger` is the state after `chain`, which holds the stakes.
+    pub fn leader(&self, chain: &[&Block], ledger: &Ledger) -> Option<Address> {
+        let stakes = ledger.stakes();
+        let total: u128 = stakes.iter().map(|(_, stake)| stake.base_units() as u128).sum();
+        let parent = chain.last()?;
+        if total == 0 {
+            return None;
+        }
+
+        let mut seed = LEADER_TAG.to_vec();
+        parent.hash().encode(&mut seed);
+        (chain.len() as u64).encode(&mut seed);
+        let mut bytes = [0; 16];
+        bytes.copy_from_slice(&sha256(&seed)[..16]);
+        let mut ticket = u128::from_be_bytes(bytes) % total;
+        for (address, stake) in stakes {
+            let stake = stake.base_units() as u128;
+            if ticket < stake {
+                return Some(address.clone());
+            }
+            ticket -= stake;
+        }
+        None
+    }
+}
+
+impl ConsensusEngine for ProofOfStake{
+    fn prepare(&self, _chain: &[&Block], header: &mut BlockHeader) {
+        header.difficulty = 0;
+    }
+
+    fn can_seal(&self, chain: &[&Block], ledger: &Ledger, key: &RSAPublicKey) -> bool {
+        self.leader(chain, ledger) == Some(Address::from(key))
+    }
+
+    fn seal(&self, b: &mut Block, ctx: &mut SealContext) -> Result<(), Interrupt> {
+        sign(b, ctx.key);
+        Ok(())
+    }
+
+    /// The block has to be signed by the receiver of its coinbase, who claims to be the leader
+    fn verify_seal(&self, b: &Block) -> Result<(), ConsensusError> {
+        match b.miner() {
+            Some(key) if signed_by(b, key) => Ok(()),
+            _ => Err(ConsensusError::InvalidSeal),
+        }
+    }
+
+    fn verify_context(&self, chain: &[&Block], ledger: &Ledger, b: &Block) -> Result<(), ConsensusError> {
+        match (self.leader(chain, ledger), b.miner()) {
+            (Some(leader), Some(miner)) if leader == Address::from(miner) => Ok(()),
+            _ => Err(ConsensusError::NotLeader{ height: chain.len() as u64 }),
+        }
+    }
+
+    fn weight(&self, _b: &Block) -> u128 {
+        1
+    }
+}
+
+/// Seal the block with a signature of `key`
+fn sign(b: &mut Block, key: &RSAPrivateKey) {
+    let padding = PaddingScheme::new_pkcs1v15_sign(Some(Hash::SHA2_256));
+    b.seal = key.sign(padding, &b.seal_hash()).expect("A SHA-256 digest always fits into the key");
+}
+
+fn signed_by(b: &Block, key: &RSAPublicKey) -> bool {
+    let padding = PaddingScheme::new_pkcs1v15_sign(Some(Hash::SHA2_256));
+    key.verify(padding, &b.seal_hash(), &b.seal).is_ok()
+}
+
+#[cfg(test)]
+mod test{
+    // Imports
+    use super::*;
+    use crate::amount::Amount;
+    use crate::blockchain::{Blockchain, BlockError};
+    use crate::genesis::GenesisConfig;
+    use crate::test_utils::block_on;
+    use crate::trader::Trader;
+    use crate::transaction::{Transaction, SignedTransaction};
+
+    #[test]
+    fn retarget_adjustment() {
+        let retarget = Retarget{ window: 10, block_time: 10, max_step: 2 };
+        // On schedule
+        assert_eq!(retarget.adjust(8, 100), 8);
+        assert_eq!(retarget.adjust(8, 199), 8);
+        // Twice as fast / slow
+        assert_eq!(retarget.adjust(8, 50), 9);
+        assert_eq!(retarget.adjust(8, 200), 7);
+        // Clamped to two bits
+        assert_eq!(retarget.adjust(8, 0), 10);
+        assert_eq!(retarget.adjust(8, 100_000), 6);
+        assert_eq!(retarget.adjust(1, 100_000), 0);
+    }
+
+    /// Build a block with `transactions` on top of the chain and seal it with `key`, paying the reward to `miner`
+    fn sealed(bc: &Blockchain, miner: &Trader, key: &RSAPrivateKey, transactions: Vec<SignedTransaction>) -> Block {
+        let mut b = block_on(&bc.blocks[bc.blocks.len() - 1], bc, miner, transactions, bc.min_timestamp());
+        bc.consensus().seal(&mut b, &mut SealContext::new(key, &mut || None)).unwrap();
+        b
+    }
+
+    #[test]
+    fn proof_of_authority() {
+        let keys: Vec<RSAPrivateKey> = (0..3).map(|_| Trader::generate_key()).collect();
+        let config = GenesisConfig{
+            validators: keys[..2].iter().map(RSAPublicKey::from).collect(),
+            ..GenesisConfig::default()
+        };
+        let traders: Vec<Trader> = keys.iter().map(|key| Trader::with_key(key.clone(), &config).unwrap()).collect();
+        let mut bc = Blockchain::from_genesis(&config).unwrap();
+
+        // The validators take turns, no work is needed
+        assert!(bc.can_seal(&traders[1].public_key));
+        assert!(!bc.can_seal(&traders[0].public_key));
+        let b = sealed(&bc, &traders[1], &keys[1], vec![]);
+        assert_eq!(b.header.difficulty, 0);
+        bc.add(b).unwrap();
+        assert!(bc.can_seal(&traders[0].public_key));
+
+        // Out of turn, not a validator at all, or tampered with after sealing
+        let b = sealed(&bc, &traders[1], &keys[1], vec![]);
+        assert_eq!(bc.add(b), Err(BlockError::Consensus(ConsensusError::WrongValidator{ height: 2 })));
+        let b = sealed(&bc, &traders[2], &keys[2], vec![]);
+        assert_eq!(bc.add(b), Err(BlockError::Consensus(ConsensusError::InvalidSeal)));
+        let mut b = sealed(&bc, &traders[0], &keys[0], vec![]);
+        b.header.timestamp += 1;
+        assert_eq!(bc.add(b), Err(BlockError::Consensus(ConsensusError::InvalidSeal)));
+
+        bc.add(sealed(&bc, &traders[0], &keys[0], vec![])).unwrap();
+        assert_eq!(bc.blocks.len(), 3);
+        assert_eq!(bc.total_work(), 3);
+        assert_eq!(bc.verify(), Ok(()));
+    }
+
+    #[test]
+    fn proof_of_stake() {
+        let keys: Vec<RSAPrivateKey> = (0..3).map(|_| Trader::generate_key()).collect();
+        let ten: Amount = "10".parse().unwrap();
+        let config = GenesisConfig{
+            allocations: vec![(RSAPublicKey::from(&keys[2]), ten)],
+            stakes: keys[..2].iter().map(|key| (RSAPublicKey::from(key), ten)).collect(),
+            ..GenesisConfig::default()
+        };
+        let traders: Vec<Trader> = keys.iter().map(|key| Trader::with_key(key.clone(), &config).unwrap()).collect();
+        let mut bc = Blockchain::from_genesis(&config).unwrap();
+        assert_eq!(bc.ledger().stake(&traders[0].public_key), ten);
+
+        // Only the elected leader may create the block, using their own key
+        let leader = traders.iter().position(|t| bc.can_seal(&t.public_key)).unwrap();
+        assert!(leader < 2);
+        let other = 1 - leader;
+        let b = sealed(&bc, &traders[other], &keys[other], vec![]);
+        assert_eq!(bc.add(b), Err(BlockError::Consensus(ConsensusError::NotLeader{ height: 1 })));
+        let b = sealed(&bc, &traders[leader], &keys[other], vec![]);
+        assert_eq!(bc.add(b), Err(BlockError::Consensus(ConsensusError::InvalidSeal)));
+
+        // The third trader locks some of their coins, which makes them eligible as well
+        let mut t = Transaction::stake(traders[2].public_key.clone(), "5".parse().unwrap());
+        t.nonce(traders[2].next_nonce());
+        let stake = traders[2].sign(t);
+        bc.add(sealed(&bc, &traders[leader], &keys[leader], vec![stake])).unwrap();
+        assert_eq!(bc.ledger().stake(&traders[2].public_key), "5".parse().unwrap());
+        let stakes: Vec<Amount> = bc.ledger().stakes().into_iter().map(|(_, stake)| stake).collect();
+        assert_eq!(stakes.iter().filter(|stake| **stake == ten).count(), 2);
+        assert!(stakes.contains(&"5".parse().unwrap()));
+
+        // The schedule is deterministic, every trader agrees on the leader of each slot
+        for _ in 0..5 {
+            let leader = traders.iter().position(|t| bc.can_seal(&t.public_key)).unwrap();
+            assert_eq!(traders.iter().filter(|t| bc.can_seal(&t.public_key)).count(), 1);
+            bc.add(sealed(&bc, &traders[leader], &keys[leader], vec![])).unwrap();
+        }
+        assert_eq!(bc.blocks.len(), 7);
+        assert_eq!(bc.verify(), Ok(()));
+    }
+}
@@ -0,0 +1,357 @@
+use rsa::{RSAPrivateKey, RSAPublicKey, PublicKeyParts, BigUint};
+use std::fmt;
+
+/// Version of the binary layout, written in front of every top level type
+pub const ENCODING_VERSION: u8 = 3;
+
+/// Types that have a canonical byte representation.
+///
+/// The encoding is deterministic and independent of the platform, so the same
+/// value produces the same bytes (and therefore the same hash) on every machine.
+/// Integers are written big-endian, variable length data is prefixed with its
+/// length as a `u64`.
+pub trait Encode {
+    /// Append the canonical byte representation of `self` to `buf`
+    fn encode(&self, buf: &mut Vec<u8>);
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        self.encode(&mut buf);
+        buf
+    }
+}
+
+/// Types that can be reconstructed from their canonical byte representation
+pub trait Decode: Sized {
+    fn decode(reader: &mut Reader) -> Result<Self, DecodeError>;
+
+    /// Decode a value that spans all of `bytes`
+    fn from_bytes(bytes: &[u8]) -> Result<Self, DecodeError> {
+        let mut reader = Reader::new(bytes);
+        let value = Self::decode(&mut reader)?;
+        if !reader.is_empty() {
+            return Err(DecodeError::TrailingBytes(reader.remaining()));
+        }
+        Ok(value)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DecodeError {
+    UnexpectedEnd,
+    TrailingBytes(usize),
+    UnsupportedVersion(u8),
+    InvalidKey,
+    InvalidValue(&'static str),
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DecodeError::UnexpectedEnd => write!(f, "unexpected end of input"),
+            DecodeError::TrailingBytes(n) => write!(f, "{} trailing bytes after value", n),
+            DecodeError::UnsupportedVersion(v) => write!(f, "unsupported encoding version {}", v),
+            DecodeError::InvalidKey => write!(f, "invalid RSA public key"),
+            DecodeError::InvalidValue(what) => write!(f, "invalid value for {}", what),
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+/// Cursor over a byte slice that is being decoded
+pub struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(bytes: &'a [u8]) -> Self {
+        Reader{ bytes, pos: 0 }
+    }
+
+    /// Consume the next `n` bytes
+    pub fn take(&mut self, n: usize) -> Result<&'a [u8], DecodeError> {
+        if self.remaining() < n {
+            return Err(DecodeError::UnexpectedEnd);
+        }
+        let slice = &self.bytes[self.pos..self.pos + n];
+        self.pos += n;
+        Ok(slice)
+    }
+
+    /// Consume a version byte and make sure it is one we understand
+    pub fn expect_version(&mut self) -> Result<(), DecodeError> {
+        match u8::decode(self)? {
+            ENCODING_VERSION => Ok(()),
+            v => Err(DecodeError::UnsupportedVersion(v)),
+        }
+    }
+
+    pub fn remaining(&self) -> usize {
+        self.bytes.len() - self.pos
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.remaining() == 0
+    }
+}
+
+impl<T: Encode + ?Sized> Encode for &T {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        (**self).encode(buf);
+    }
+}
+
+impl Encode for u8 {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        buf.push(*self);
+    }
+}
+
+impl Decode for u8 {
+    fn decode(reader: &mut Reader) -> Result<Self, DecodeError> {
+        Ok(reader.take(1)?[0])
+    }
+}
+
+macro_rules! impl_int {
+    ($($t:ty),*) => {$(
+        impl Encode for $t {
+            fn encode(&self, buf: &mut Vec<u8>) {
+                buf.extend_from_slice(&self.to_be_bytes());
+            }
+        }
+
+        impl Decode for $t {
+            fn decode(reader: &mut Reader) -> Result<Self, DecodeError> {
+                let mut bytes = [0; std::mem::size_of::<$t>()];
+                bytes.copy_from_slice(reader.take(std::mem::size_of::<$t>())?);
+                Ok(<$t>::from_be_bytes(bytes))
+            }
+        }
+    )*};
+}
+
+impl_int!(u32, u64, i32);
+
+impl Encode for bool {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        (*self as u8).encode(buf);
+    }
+}
+
+impl Decode for bool {
+    fn decode(reader: &mut Reader) -> Result<Self, DecodeError> {
+        match u8::decode(reader)? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(DecodeError::InvalidValue("bool")),
+        }
+    }
+}
+
+/// Read a length prefix and make sure that at least that many bytes follow
+fn decode_len(reader: &mut Reader) -> Result<usize, DecodeError> {
+    let len = u64::decode(reader)?;
+    if len > reader.remaining() as u64 {
+        return Err(DecodeError::UnexpectedEnd);
+    }
+    Ok(len as usize)
+}
+
+impl Encode for [u8] {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        (self.len() as u64).encode(buf);
+        buf.extend_from_slice(self);
+    }
+}
+
+impl Encode for Vec<u8> {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        self.as_slice().encode(buf);
+    }
+}
+
+impl Decode for Vec<u8> {
+    fn decode(reader: &mut Reader) -> Result<Self, DecodeError> {
+        let len = decode_len(reader)?;
+        Ok(reader.take(len)?.to_vec())
+    }
+}
+
+impl Encode for str {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        self.as_bytes().encode(buf);
+    }
+}
+
+impl Encode for String {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        self.as_str().encode(buf);
+    }
+}
+
+impl Decode for String {
+    fn decode(reader: &mut Reader) -> Result<Self, DecodeError> {
+        String::from_utf8(Vec::<u8>::decode(reader)?)
+            .map_err(|_| DecodeError::InvalidValue("utf-8 string"))
+    }
+}
+
+impl Encode for RSAPublicKey {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        self.n().to_bytes_be().encode(buf);
+        self.e().to_bytes_be().encode(buf);
+    }
+}
+
+impl Decode for RSAPublicKey {
+    fn decode(reader: &mut Reader) -> Result<Self, DecodeError> {
+        let n = BigUint::from_bytes_be(&Vec::<u8>::decode(reader)?);
+        let e = BigUint::from_bytes_be(&Vec::<u8>::decode(reader)?);
+        RSAPublicKey::new(n, e).map_err(|_| DecodeError::InvalidKey)
+    }
+}
+
+/// Private keys are only written to local key files, never into blocks
+impl Encode for RSAPrivateKey {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        ENCODING_VERSION.encode(buf);
+        RSAPublicKey::from(self).encode(buf);
+        self.d().to_bytes_be().encode(buf);
+        let primes: Vec<Vec<u8>> = self.primes().iter().map(|p| p.to_bytes_be()).collect();
+        encode_seq(primes.iter(), buf);
+    }
+}
+
+impl Decode for RSAPrivateKey {
+    fn decode(reader: &mut Reader) -> Result<Self, DecodeError> {
+        reader.expect_version()?;
+        let public = RSAPublicKey::decode(reader)?;
+        let d = BigUint::from_bytes_be(&Vec::<u8>::decode(reader)?);
+        let primes = decode_seq::<Vec<u8>>(reader)?.iter().map(|p| BigUint::from_bytes_be(p)).collect();
+        let key = RSAPrivateKey::from_components(public.n().clone(), public.e().clone(), d, primes);
+        key.validate().map_err(|_| DecodeError::InvalidKey)?;
+        Ok(key)
+    }
+}
+
+/// Encode a sequence of values, prefixed with the number of elements
+pub fn encode_seq<'a, T, I>(items: I, buf: &mut Vec<u8>)
+where T: Encode + 'a, I: ExactSizeIterator<Item = &'a T> {
+    (items.len() as u64).encode(buf);
+    for item in items {
+        item.encode(buf);
+    }
+}
+
+/// Decode a sequence written by `encode_seq`
+pub fn decode_seq<T: Decode>(reader: &mut Reader) -> Result<Vec<T>, DecodeError> {
+    let len = u64::decode(reader)?;
+    // Every element takes up at least one byte, which bounds the allocation
+    if len > reader.remaining() as u64 {
+        return Err(DecodeError::UnexpectedEnd);
+    }
+    let mut items = Vec::with_capacity(len as usize);
+    for _ in 0..len {
+        items.push(T::decode(reader)?);
+    }
+    Ok(items)
+}
+
+#[cfg(test)]
+mod test{
+    // Imports
+    use super::*;
+    use crate::blockchain::{Block, BlockHeader};
+    use crate::merkletree::MerkleTree;
+    use crate::trader::Trader;
+    use crate::transaction::Transaction;
+    use crate::amount::Amount;
+    use crate::utils::sha256_digest;
+
+    fn round_trip<T: Encode + Decode>(value: &T) -> T {
+        let bytes = value.to_bytes();
+        let decoded = T::from_bytes(&bytes).unwrap();
+        assert_eq!(decoded.to_bytes(), bytes);
+        decoded
+    }
+
+    #[test]
+    fn primitives() {
+        assert_eq!(round_trip(&0xdead_beef_u32), 0xdead_beef);
+        assert_eq!(round_trip(&u64::MAX), u64::MAX);
+        assert_eq!(round_trip(&-7i32), -7);
+        assert_eq!(round_trip(&"Genesis".to_string()), "Genesis");
+        assert_eq!(round_trip(&vec![1u8, 2, 3]), vec![1, 2, 3]);
+
+        // Fixed endianness
+        assert_eq!(1u32.to_bytes(), vec![0, 0, 0, 1]);
+    }
+
+    #[test]
+    fn malformed_input() {
+        assert_eq!(u64::from_bytes(&[0, 1]), Err(DecodeError::UnexpectedEnd));
+        assert_eq!(u8::from_bytes(&[0, 1]), Err(DecodeError::TrailingBytes(1)));
+        // Length prefix pointing past the end of the input
+        let mut bytes = 100u64.to_bytes();
+        bytes.push(0);
+        assert_eq!(Vec::<u8>::from_bytes(&bytes), Err(DecodeError::UnexpectedEnd));
+    }
+
+    #[test]
+    fn consensus_types() {
+        let trader_1 = Trader::new();
+        let trader_2 = Trader::new();
+
+        let t = Transaction::new(trader_1.public_key.clone(), trader_2.public_key.clone(), Amount::from_coins(1).unwrap());
+        let decoded = round_trip(&t);
+        assert_eq!(decoded.sender, t.sender);
+        assert_eq!(decoded.receiver, t.receiver);
+
+        let st = trader_1.sign(t);
+        let decoded = round_trip(&st);
+        assert_eq!(decoded.verify(), Ok(()));
+
+        let mut tree = MerkleTree::new();
+        tree.add(st.clone());
+        tree.add(st);
+        let decoded = round_trip(&tree);
+        assert_eq!(decoded.get_root_hash(), tree.get_root_hash());
+        assert_eq!(decoded.verify(), Ok(()));
+
+        let mut header = BlockHeader::new(vec![0; 32], 1, 1_600_000_000);
+        header.nonce = 42;
+        let block = Block::new(header, tree);
+        let decoded = round_trip(&block);
+        assert_eq!(decoded.header, block.header);
+        assert_eq!(decoded.verify(), Ok(()));
+        assert_eq!(sha256_digest(&decoded), sha256_digest(&block));
+    }
+
+    #[test]
+    fn private_key() {
+        let key = Trader::generate_key();
+        let decoded = round_trip(&key);
+        assert_eq!(RSAPublicKey::from(&decoded), RSAPublicKey::from(&key));
+
+        // A key whose parts don't belong together is rejected
+        let mut bytes = key.to_bytes();
+        let last = bytes.len() - 1;
+        bytes[last] ^= 1;
+        assert_eq!(RSAPrivateKey::from_bytes(&bytes).unwrap_err(), DecodeError::InvalidKey);
+    }
+
+    #[test]
+    fn unknown_version() {
+        let trader = Trader::new();
+        let t = Transaction::new(trader.public_key.clone(), trader.public_key.clone(), Amount::from_coins(1).unwrap());
+        let mut bytes = t.to_bytes();
+        bytes[0] = ENCODING_VERSION + 1;
+        assert_eq!(
+            Transaction::from_bytes(&bytes).unwrap_err(),
+            DecodeError::UnsupportedVersion(ENCODING_VERSION + 1)
+        );
+    }
+}
@@ -0,0 +1,72 @@
+use crate::blockchain::BlockError;
+use crate::consensus::ConsensusError;
+use crate::encoding::DecodeError;
+use crate::genesis::GenesisError;
+use crate::ledger::LedgerError;
+use crate::mempool::MempoolError;
+use crate::merkletree::MerkleError;
+use crate::storage::StorageError;
+use crate::trader::NetworkError;
+use crate::transaction::TxError;
+use std::fmt;
+
+/// Error returned by the entry points of this crate, like creating a `Trader` or opening a
+/// stored `Blockchain`, which can fail for reasons from several modules.
+/// Everything else returns the error type of its module, which converts into this one.
+#[derive(Debug)]
+pub enum Error {
+    Tx(TxError),
+    Block(BlockError),
+    Consensus(ConsensusError),
+    Merkle(MerkleError),
+    Ledger(LedgerError),
+    Mempool(MempoolError),
+    Network(NetworkError),
+    Decode(DecodeError),
+    Genesis(GenesisError),
+    Storage(StorageError),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Tx(e) => write!(f, "invalid transaction: {}", e),
+            Error::Block(e) => write!(f, "invalid block: {}", e),
+            Error::Consensus(e) => write!(f, "consensus rule broken: {}", e),
+            Error::Merkle(e) => write!(f, "invalid merkle tree: {}", e),
+            Error::Ledger(e) => write!(f, "rejected by the ledger: {}", e),
+            Error::Mempool(e) => write!(f, "rejected by the mempool: {}", e),
+            Error::Network(e) => write!(f, "network error: {}", e),
+            Error::Decode(e) => write!(f, "malformed data: {}", e),
+            Error::Genesis(e) => write!(f, "{}", e),
+            Error::Storage(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+macro_rules! impl_from {
+    ($($variant:ident($t:ty)),*) => {
+        $(
+            impl From<$t> for Error {
+                fn from(e: $t) -> Self {
+                    Error::$variant(e)
+                }
+            }
+        )*
+    };
+}
+
+impl_from!(
+    Tx(TxError),
+    Block(BlockError),
+    Consensus(ConsensusError),
+    Merkle(MerkleError),
+    Ledger(LedgerError),
+    Mempool(MempoolError),
+    Network(NetworkError),
+    Decode(DecodeError),
+    Genesis(GenesisError),
+    Storage(StorageError)
+);
@@ -0,0 +1,308 @@
+use crate::amount::Amount;
+use crate::blockchain::{Block, BlockHeader, DEFAULT_BLOCK_REWARD, MAX_DIFFICULTY};
+use crate::consensus::{ConsensusEngine, ProofOfAuthority, ProofOfStake, ProofOfWork, Retarget};
+use crate::encoding::{Encode, Decode};
+use crate::ledger::LedgerMode;
+use crate::merkletree::MerkleTree;
+use crate::transaction::{Transaction, SignedTransaction};
+use crate::utils::{sha256, to_hex, from_hex};
+use rsa::RSAPublicKey;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+use std::sync::Arc;
+
+/// Everything that is needed to build the genesis block of a network.
+/// Two chains created from equal configs share the same genesis block, which
+/// allows separate processes to take part in the same network.
+///
+/// Configs are stored as plain text, one `key = value` pair per line:
+/// ```text
+/// chain_id = testnet
+/// timestamp = 1600000000
+/// difficulty = 8
+/// ledger_mode = account
+/// block_reward = 50
+/// retarget_window = 10
+/// block_time = 10
+/// retarget_max_step = 2
+/// allocation = <hex encoded public key> 100.5
+/// validator = <hex encoded public key>
+/// stake = <hex encoded public key> 10
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub struct GenesisConfig {
+    /// Name of the network, its hash is the previous hash of the genesis block
+    pub chain_id: String,
+    pub timestamp: u64,
+    /// Number of leading zero bits the hash of the first blocks needs to have,
+    /// later blocks are retargeted according to `retarget`. Unused with proof-of-authority.
+    pub difficulty: u32,
+    pub ledger_mode: LedgerMode,
+    pub block_reward: Amount,
+    pub retarget: Retarget,
+    /// Coins that exist right from the start, in order
+    pub allocations: Vec<(RSAPublicKey, Amount)>,
+    /// Keys that take turns sealing blocks (proof-of-authority).
+    /// If there are none, blocks are mined with proof-of-work.
+    pub validators: Vec<RSAPublicKey>,
+    /// Coins that are locked as stake right from the start, in order.
+    /// If there are any and no validators, blocks are created with proof-of-stake.
+    pub stakes: Vec<(RSAPublicKey, Amount)>,
+}
+
+#[derive(Debug)]
+pub enum GenesisError {
+    Io(std::io::Error),
+    Parse{ line: usize, message: String },
+}
+
+impl fmt::Display for GenesisError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            GenesisError::Io(e) => write!(f, "failed to read genesis config: {}", e),
+            GenesisError::Parse{ line, message } => write!(f, "genesis config line {}: {}", line, message),
+        }
+    }
+}
+
+impl std::error::Error for GenesisError {}
+
+impl From<std::io::Error> for GenesisError {
+    fn from(e: std::io::Error) -> Self {
+        GenesisError::Io(e)
+    }
+}
+
+impl Default for GenesisConfig {
+    fn default() -> Self {
+        GenesisConfig {
+            chain_id: "Genesis".to_string(),
+            timestamp: 0,
+            difficulty: 8,
+            ledger_mode: LedgerMode::default(),
+            block_reward: DEFAULT_BLOCK_REWARD,
+            retarget: Retarget::default(),
+            allocations: Vec::new(),
+            validators: Vec::new(),
+            stakes: Vec::new(),
+        }
+    }
+}
+
+impl GenesisConfig {
+    /// Build the genesis block, which holds one unsigned allocation followed by
+    /// one unsigned stake transaction per stake.
+    /// The result only depends on the config, never on the local machine.
+    pub fn block(&self) -> Block {
+        let mut transactions = MerkleTree::new();
+        for (ix, (key, amount)) in self.allocations.iter().enumerate() {
+            let t = Transaction::allocation(key.clone(), *amount, ix as u64);
+            transactions.add(SignedTransaction{ transaction: t, signature: Vec::new() });
+        }
+        for (ix, (key, amount)) in self.stakes.iter().enumerate() {
+            let mut t = Transaction::stake(key.clone(), *amount);
+            t.fee = Amount::ZERO;
+            t.nonce(ix as u64);
+            transactions.add(SignedTransaction{ transaction: t, signature: Vec::new() });
+        }
+        // The genesis block has no parent, the hash of the chain id keeps networks apart instead
+        let mut header = BlockHeader::new(sha256(self.chain_id.as_bytes()), 0, self.timestamp);
+        header.difficulty = self.difficulty;
+        Block::new(header, transactions)
+    }
+
+    /// The consensus engine of the network
+    pub fn consensus(&self) -> Arc<dyn ConsensusEngine> {
+        if !self.validators.is_empty() {
+            Arc::new(ProofOfAuthority::new(self.validators.clone()))
+        }
+        else if !self.stakes.is_empty() {
+            Arc::new(ProofOfStake)
+        }
+        else {
+            Arc::new(ProofOfWork::new(self.retarget))
+        }
+    }
+
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self, GenesisError> {
+        Self::parse(&fs::read_to_string(path)?)
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), GenesisError> {
+        fs::write(path, self.to_string())?;
+        Ok(())
+    }
+
+    /// Parse a config in the format written by `Display`.
+    /// Missing keys keep their default value, empty lines and lines starting with `#` are ignored.
+    pub fn parse(s: &str) -> Result<Self, GenesisError> {
+        let mut config = GenesisConfig::default();
+        for (ix, line) in s.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let error = |message: &str| GenesisError::Parse{ line: ix + 1, message: message.to_string() };
+            let public_key = |hex: Option<&str>| {
+                hex.and_then(from_hex)
+                    .and_then(|bytes| RSAPublicKey::from_bytes(&bytes).ok())
+                    .ok_or_else(|| error("invalid public key"))
+            };
+            let (key, value) = match line.find('=') {
+                Some(pos) => (line[..pos].trim(), line[pos + 1..].trim()),
+                None => return Err(error("expected `key = value`")),
+            };
+            match key {
+                "chain_id" => config.chain_id = value.to_string(),
+                "timestamp" => config.timestamp = value.parse().map_err(|_| error("invalid timestamp"))?,
+                "difficulty" => {
+                    config.difficulty = match value.parse() {
+                        Ok(difficulty) if difficulty <= MAX_DIFFICULTY => difficulty,
+                        _ => return Err(error(&format!("difficulty must be a number up to {}", MAX_DIFFICULTY))),
+                    }
+                },
+                "ledger_mode" => {
+                    config.ledger_mode = match value {
+                        "account" => LedgerMode::Account,
+                        "utxo" => LedgerMode::Utxo,
+                        _ => return Err(error("ledger_mode must be `account` or `utxo`")),
+                    }
+                },
+                "retarget_window" => config.retarget.window = value.parse().map_err(|_| error("invalid retarget_window"))?,
+                "block_time" => config.retarget.block_time = value.parse().map_err(|_| error("invalid block_time"))?,
+                "retarget_max_step" => config.retarget.max_step = value.parse().map_err(|_| error("invalid retarget_max_step"))?,
+                "block_reward" => config.block_reward = value.parse().map_err(|e| error(&format!("{}", e)))?,
+                "allocation" | "stake" => {
+                    let mut parts = value.split_whitespace();
+                    let owner = public_key(parts.next())?;
+                    let amount = parts.next()
+                        .ok_or_else(|| error("missing amount"))?
+                        .parse()
+                        .map_err(|e| error(&format!("{}", e)))?;
+                    if parts.next().is_some() {
+                        return Err(error(&format!("expected `{} = <key> <amount>`", key)));
+                    }
+                    if key == "stake" {
+                        config.stakes.push((owner, amount));
+                    }
+                    else {
+                        config.allocations.push((owner, amount));
+                    }
+                },
+                "validator" => config.validators.push(public_key(Some(value))?),
+                _ => return Err(error(&format!("unknown key `{}`", key))),
+            }
+        }
+        Ok(config)
+    }
+}
+
+impl fmt::Display for GenesisConfig {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "chain_id = {}", self.chain_id)?;
+        writeln!(f, "timestamp = {}", self.timestamp)?;
+        writeln!(f, "difficulty = {}", self.difficulty)?;
+        let mode = match self.ledger_mode {
+            LedgerMode::Account => "account",
+            LedgerMode::Utxo => "utxo",
+        };
+        writeln!(f, "ledger_mode = {}", mode)?;
+        writeln!(f, "block_reward = {}", self.block_reward)?;
+        writeln!(f, "retarget_window = {}", self.retarget.window)?;
+        writeln!(f, "block_time = {}", self.retarget.block_time)?;
+        writeln!(f, "retarget_max_step = {}", self.retarget.max_step)?;
+        for (key, amount) in self.allocations.iter() {
+            writeln!(f, "allocation = {} {}", to_hex(&key.to_bytes()), amount)?;
+        }
+        for key in self.validators.iter() {
+            writeln!(f, "validator = {}", to_hex(&key.to_bytes()))?;
+        }
+        for (key, amount) in self.stakes.iter() {
+            writeln!(f, "stake = {} {}", to_hex(&key.to_bytes()), amount)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test{
+    // Imports
+    use super::*;
+    use crate::blockchain::Blockchain;
+    use crate::error::Error;
+    use crate::ledger::LedgerError;
+    use crate::trader::Trader;
+    use crate::utils::sha256_digest;
+    use std::{thread, time::Duration};
+
+    #[test]
+    fn deterministic_genesis() {
+        let alice = Trader::new();
+        let mut config = GenesisConfig::default();
+        config.allocations.push((alice.public_key.clone(), "100".parse().unwrap()));
+
+        let a = Blockchain::from_genesis(&config).unwrap();
+        thread::sleep(Duration::from_millis(1100));
+        let b = Blockchain::from_genesis(&config).unwrap();
+        assert_eq!(sha256_digest(&a.blocks[0]), sha256_digest(&b.blocks[0]));
+        assert_eq!(a.ledger().balance(&alice.public_key), "100".parse().unwrap());
+
+        // A different network has a different genesis block
+        config.chain_id = "othernet".to_string();
+        let c = Blockchain::from_genesis(&config).unwrap();
+        assert_ne!(sha256_digest(&a.blocks[0]), sha256_digest(&c.blocks[0]));
+    }
+
+    #[test]
+    fn config_file_round_trip() {
+        let alice = Trader::new();
+        let bob = Trader::new();
+        let config = GenesisConfig {
+            chain_id: "testnet".to_string(),
+            timestamp: 1_600_000_000,
+            difficulty: 12,
+            ledger_mode: LedgerMode::Utxo,
+            block_reward: "12.5".parse().unwrap(),
+            retarget: Retarget{ window: 5, block_time: 60, max_step: 1 },
+            allocations: vec![
+                (alice.public_key.clone(), "100".parse().unwrap()),
+                (bob.public_key.clone(), "0.5".parse().unwrap()),
+            ],
+            validators: vec![bob.public_key.clone()],
+            stakes: vec![(alice.public_key.clone(), "10".parse().unwrap())],
+        };
+
+        let path = std::env::temp_dir().join(format!("genesis-{}.conf", std::process::id()));
+        config.save(&path).unwrap();
+        let loaded = GenesisConfig::from_file(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(loaded, config);
+
+        let a = Blockchain::from_genesis(&config).unwrap();
+        let b = Blockchain::from_genesis(&loaded).unwrap();
+        assert_eq!(sha256_digest(&a.blocks[0]), sha256_digest(&b.blocks[0]));
+        assert_eq!(b.ledger().balance(&bob.public_key), "0.5".parse().unwrap());
+    }
+
+    #[test]
+    fn reject_malformed_config() {
+        assert!(matches!(GenesisConfig::parse("difficulty = hard"), Err(GenesisError::Parse{ line: 1, .. })));
+        assert!(matches!(GenesisConfig::parse("difficulty = 257"), Err(GenesisError::Parse{ line: 1, .. })));
+        assert!(matches!(GenesisConfig::parse("\n# comment\nnonsense"), Err(GenesisError::Parse{ line: 3, .. })));
+        assert!(matches!(GenesisConfig::parse("allocation = 00ff 1"), Err(GenesisError::Parse{ .. })));
+        assert!(matches!(GenesisConfig::parse("validator = 00ff"), Err(GenesisError::Parse{ .. })));
+        assert!(matches!(GenesisConfig::parse("color = blue"), Err(GenesisError::Parse{ .. })));
+    }
+
+    #[test]
+    fn reject_invalid_genesis_block() {
+        let key = Trader::generate_key();
+        let huge = Amount::from_base_units(u64::MAX);
+        let config = GenesisConfig {
+            allocations: vec![(RSAPublicKey::from(&key), huge), (RSAPublicKey::from(&key), huge)],
+            ..GenesisConfig::default()
+        };
+        assert!(matches!(Trader::with_key(key, &config), Err(Error::Ledger(LedgerError::Overflow))));
+    }
+}
@@ -0,0 +1,72 @@
+use crate::blockchain::Block;
+use crate::ledger::Address;
+use rsa::RSAPublicKey;
+use std::collections::HashMap;
+
+/// Where a transaction is stored on the active chain
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TxLocation{
+    /// Height of the block that contains the transaction
+    pub height: u64,
+    /// Position of the transaction within the block, the coinbase is at zero
+    pub index: usize,
+}
+
+/// Lookups into the active chain by block hash, transaction hash and address.
+/// Blocks are pushed as they extend the chain, after a reorganization the index is rebuilt.
+#[derive(Debug, Clone, Default)]
+pub struct ChainIndex{
+    /// Height of every block, keyed by block hash
+    heights: HashMap<Vec<u8>, u64>,
+    /// Location of every transaction, keyed by transaction hash
+    transactions: HashMap<Vec<u8>, TxLocation>,
+    /// Locations of the transactions that send coins from or to an address, in chain order
+    addresses: HashMap<Address, Vec<TxLocation>>,
+}
+
+impl ChainIndex{
+    /// Index every block of `chain`, which starts at the genesis block
+    pub fn new(chain: &[Block]) -> Self {
+        let mut index = ChainIndex::default();
+        for b in chain {
+            index.push(b);
+        }
+        index
+    }
+
+    /// Index a block that extends the chain
+    pub fn push(&mut self, b: &Block) {
+        let height = b.header.height;
+        self.heights.insert(b.hash(), height);
+        for (index, st) in b.transactions.leaves().iter().enumerate() {
+            let t = &st.transaction;
+            let location = TxLocation{ height, index };
+            self.transactions.insert(t.hash(), location);
+
+            let mut touched: Vec<Address> = std::iter::once(&t.sender)
+                .chain(std::iter::once(&t.receiver))
+                .chain(t.outputs.iter().map(|o| &o.receiver))
+                .map(Address::from)
+                .collect();
+            touched.sort();
+            touched.dedup();
+            for address in touched {
+                self.addresses.entry(address).or_default().push(location);
+            }
+        }
+    }
+
+    /// Height of the block with hash `hash`, if it is part of the active chain
+    pub fn height(&self, hash: &[u8]) -> Option<u64> {
+        self.heights.get(hash).copied()
+    }
+
+    pub fn transaction(&self, hash: &[u8]) -> Option<TxLocation> {
+        self.transactions.get(hash).copied()
+    }
+
+    /// Every transaction that `key` sent or received coins with, oldest first
+    pub fn transactions_of(&self, key: &RSAPublicKey) -> &[TxLocation] {
+        self.addresses.get(&Address::from(key)).map(Vec::as_slice).unwrap_or(&[])
+    }
+}
@@ -0,0 +1,587 @@
+use crate::amount::Amount;
+use crate::blockchain::Block;
+use crate::encoding::Encode;
+use crate::transaction::{Transaction, TxKind, OutPoint, TxOutput};
+use crate::utils::{sha256, sha256_digest, to_hex};
+use rsa::RSAPublicKey;
+use std::collections::HashMap;
+use std::fmt;
+
+/// Identifies an account by the hash of its public key
+#[derive(Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Address(Vec<u8>);
+
+impl From<&RSAPublicKey> for Address {
+    fn from(key: &RSAPublicKey) -> Self {
+        Address(sha256_digest(key))
+    }
+}
+
+impl Encode for Address {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        self.0.encode(buf);
+    }
+}
+
+/// How ownership of coins is tracked
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LedgerMode {
+    /// Every public key has a single balance that transactions are debited from
+    #[default]
+    Account,
+    /// Transactions spend unspent outputs of previous transactions (Bitcoin style)
+    Utxo,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LedgerError {
+    /// The sender tried to spend more than they own
+    Overspend{ balance: Amount, required: Amount },
+    /// A balance or the sum of the transaction amounts does not fit into an `Amount`
+    Overflow,
+    /// Transactions in account mode must not spend outputs
+    UnexpectedInputs,
+    /// Transactions in UTXO mode must spend at least one output
+    NoInputs,
+    /// The input is not in the UTXO set, it was either spent already or never existed
+    MissingInput(OutPoint),
+    /// The input is spent more than once within the same transaction
+    DuplicateInput(OutPoint),
+    /// The input belongs to somebody other than the sender
+    NotOwner(OutPoint),
+    /// The inputs don't add up to the outputs plus fee and tip
+    Unbalanced{ inputs: Amount, outputs: Amount },
+    /// A transaction with this nonce was already applied, the transaction is a duplicate
+    NonceTooLow{ expected: u64, got: u64 },
+    /// Transactions of the sender with a lower nonce are still missing
+    NonceGap{ expected: u64, got: u64 },
+    /// The first transaction of a block has to be a coinbase
+    MissingCoinbase,
+    /// The genesis block may only hold allocations and stakes
+    UnexpectedTransaction,
+    /// A genesis allocation outside of the genesis block
+    UnexpectedAllocation,
+    /// A coinbase anywhere but at the start of a block
+    UnexpectedCoinbase,
+    /// The coinbase has fees, inputs, additional outputs or the wrong height
+    MalformedCoinbase,
+    /// The coinbase doesn't pay exactly the block reward plus all fees and tips
+    InvalidCoinbaseAmount{ expected: Amount, got: Amount },
+}
+
+impl fmt::Display for LedgerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LedgerError::Overspend{ balance, required } => {
+                write!(f, "overspend: balance is {} but {} is required", balance, required)
+            },
+            LedgerError::Overflow => write!(f, "amount overflow"),
+            LedgerError::UnexpectedInputs => write!(f, "transaction spends outputs in account mode"),
+            LedgerError::NoInputs => write!(f, "transaction has no inputs"),
+            LedgerError::MissingInput(o) => write!(f, "input {}:{} is not unspent", to_hex(&o.tx_hash), o.index),
+            LedgerError::DuplicateInput(o) => write!(f, "input {}:{} is spent twice", to_hex(&o.tx_hash), o.index),
+            LedgerError::NotOwner(o) => write!(f, "input {}:{} is not owned by the sender", to_hex(&o.tx_hash), o.index),
+            LedgerError::Unbalanced{ inputs, outputs } => {
+                write!(f, "inputs of {} don't match outputs, fee and tip of {}", inputs, outputs)
+            },
+            LedgerError::NonceTooLow{ expected, got } => {
+                write!(f, "duplicate transaction: nonce {} was already used, expected {}", got, expected)
+            },
+            LedgerError::NonceGap{ expected, got } => {
+                write!(f, "nonce gap: expected nonce {} but got {}", expected, got)
+            },
+            LedgerError::MissingCoinbase => write!(f, "block does not start with a coinbase"),
+            LedgerError::UnexpectedTransaction => write!(f, "genesis block holds a regular transaction"),
+            LedgerError::UnexpectedAllocation => write!(f, "genesis allocation outside of the genesis block"),
+            LedgerError::UnexpectedCoinbase => write!(f, "coinbase outside of the first position"),
+            LedgerError::MalformedCoinbase => write!(f, "malformed coinbase"),
+            LedgerError::InvalidCoinbaseAmount{ expected, got } => {
+                write!(f, "coinbase pays {} but {} is expected", got, expected)
+            },
+        }
+    }
+}
+
+impl std::error::Error for LedgerError {}
+
+/// Who owns which coins, derived by applying the blocks of a chain in order.
+/// Depending on the mode, ownership is either tracked as a balance per account
+/// or as a set of unspent transaction outputs.
+#[derive(Debug, Clone, Default)]
+pub struct Ledger {
+    mode: LedgerMode,
+    balances: HashMap<Address, Amount>,
+    utxos: HashMap<OutPoint, TxOutput>,
+    /// Coins locked by stake transactions, which can't be spent anymore
+    stakes: HashMap<Address, Amount>,
+    /// The nonce expected for the next transaction of each sender
+    nonces: HashMap<Address, u64>,
+    /// Number of outputs created through `credit`, used to derive unique outpoints
+    minted: u64,
+}
+
+impl Ledger {
+    pub fn new(mode: LedgerMode) -> Self {
+        Ledger{ mode, ..Ledger::default() }
+    }
+
+    pub fn mode(&self) -> LedgerMode {
+        self.mode
+    }
+
+    pub fn balance(&self, key: &RSAPublicKey) -> Amount {
+        match self.mode {
+            LedgerMode::Account => {
+                self.balances.get(&Address::from(key)).copied().unwrap_or_default()
+            },
+            LedgerMode::Utxo => {
+                self.unspent_outputs(key)
+                    .iter()
+                    .fold(Amount::ZERO, |sum, (_, o)| sum.checked_add(o.amount).unwrap_or(sum))
+            },
+        }
+    }
+
+    /// Coins locked as stake of `key`, they are not part of the balance
+    pub fn stake(&self, key: &RSAPublicKey) -> Amount {
+        self.stakes.get(&Address::from(key)).copied().unwrap_or_default()
+    }
+
+    /// Every stake that isn't zero, ordered by address
+    pub fn stakes(&self) -> Vec<(&Address, Amount)> {
+        let mut stakes: Vec<(&Address, Amount)> = self.stakes
+            .iter()
+            .filter(|(_, stake)| **stake > Amount::ZERO)
+            .map(|(address, stake)| (address, *stake))
+            .collect();
+        stakes.sort();
+        stakes
+    }
+
+    /// The nonce the next transaction of `key` has to carry
+    pub fn next_nonce(&self, key: &RSAPublicKey) -> u64 {
+        self.nonces.get(&Address::from(key)).copied().unwrap_or(0)
+    }
+
+    /// All unspent outputs owned by `key`, always empty in account mode
+    pub fn unspent_outputs(&self, key: &RSAPublicKey) -> Vec<(OutPoint, TxOutput)> {
+        self.utxos
+            .iter()
+            .filter(|(_, o)| o.receiver == *key)
+            .map(|(p, o)| (p.clone(), o.clone()))
+            .collect()
+    }
+
+    /// Add coins to an account.
+    /// In UTXO mode this creates a new output that doesn't belong to any transaction.
+    pub fn credit(&mut self, key: &RSAPublicKey, amount: Amount) -> Result<(), LedgerError> {
+        match self.mode {
+            LedgerMode::Account => {
+                let balance = self.balances.entry(Address::from(key)).or_default();
+                *balance = balance.checked_add(amount).ok_or(LedgerError::Overflow)?;
+            },
+            LedgerMode::Utxo => {
+                let mut seed = b"credit".to_vec();
+                self.minted.encode(&mut seed);
+                self.minted += 1;
+                let outpoint = OutPoint{ tx_hash: sha256(&seed), index: 0 };
+                self.utxos.insert(outpoint, TxOutput{ receiver: key.clone(), amount });
+            },
+        }
+        Ok(())
+    }
+
+    /// Check whether the sender can afford the transaction, without applying it
+    pub fn check_transaction(&self, t: &Transaction) -> Result<(), LedgerError> {
+        // Coinbases are only valid as part of a block, see `apply_block`
+        if t.is_coinbase() {
+            return Err(LedgerError::UnexpectedCoinbase);
+        }
+        if t.is_allocation() {
+            return Err(LedgerError::UnexpectedAllocation);
+        }
+        let expected = self.next_nonce(&t.sender);
+        if t.nonce < expected {
+            return Err(LedgerError::NonceTooLow{ expected, got: t.nonce });
+        }
+        if t.nonce > expected {
+            return Err(LedgerError::NonceGap{ expected, got: t.nonce });
+        }
+        match self.mode {
+            LedgerMode::Account => self.check_account_transaction(t),
+            LedgerMode::Utxo => self.check_utxo_transaction(t),
+        }
+    }
+
+    /// Move the coins from the sender to the receivers.
+    /// The ledger is left unchanged if the transaction is rejected.
+    pub fn apply_transaction(&mut self, t: &Transaction) -> Result<(), LedgerError> {
+        self.check_transaction(t)?;
+        match self.mode {
+            LedgerMode::Account => {
+                // Compute the new balances of every account involved first,
+                // so an overflow on the receivers side leaves the ledger untouched
+                let mut next: HashMap<Address, Amount> = HashMap::new();
+                for output in Self::account_outputs(t) {
+                    let address = Address::from(&output.receiver);
+                    let current = self.balances.get(&address).copied().unwrap_or_default();
+                    let balance = next.entry(address).or_insert(current);
+                    *balance = balance.checked_add(output.amount).ok_or(LedgerError::Overflow)?;
+                }
+                let address = Address::from(&t.sender);
+                let current = self.balances.get(&address).copied().unwrap_or_default();
+                let balance = next.entry(address).or_insert(current);
+                *balance = balance.checked_sub(Self::debit_of(t)?).ok_or(LedgerError::Overflow)?;
+                self.balances.extend(next);
+            },
+            LedgerMode::Utxo => {
+                for input in t.inputs.iter() {
+                    self.utxos.remove(input);
+                }
+                let tx_hash = t.hash();
+                for (index, output) in t.all_outputs().into_iter().enumerate() {
+                    let outpoint = OutPoint{ tx_hash: tx_hash.clone(), index: index as u32 };
+                    self.utxos.insert(outpoint, output);
+                }
+            },
+        }
+        if t.is_stake() {
+            self.lock_stake(t)?;
+        }
+        self.nonces.insert(Address::from(&t.sender), t.nonce + 1);
+        Ok(())
+    }
+
+    /// Apply all transactions within the block.
+    /// The block has to start with a coinbase for its height that pays exactly `reward`
+    /// plus the fees and tips of all other transactions.
+    /// Either the whole block is applied or, on error, nothing at all.
+    pub fn apply_block(&mut self, b: &Block, reward: Amount) -> Result<(), LedgerError> {
+        let leaves = b.transactions.leaves();
+        let (coinbase, transactions) = match leaves.split_first() {
+            Some((first, rest)) if first.transaction.is_coinbase() => (&first.transaction, rest),
+            _ => return Err(LedgerError::MissingCoinbase),
+        };
+
+        let mut next = self.clone();
+        let mut expected = reward;
+        for st in transactions {
+            next.apply_transaction(&st.transaction)?;
+            expected = expected
+                .checked_add(st.transaction.fee)
+                .and_then(|a| a.checked_add(st.transaction.tip))
+                .ok_or(LedgerError::Overflow)?;
+        }
+        next.apply_coinbase(coinbase, b.header.height, expected)?;
+        *self = next;
+        Ok(())
+    }
+
+    /// Credit the allocations and stakes of the genesis block, which consists of unsigned
+    /// allocations and stakes only. Genesis stakes are created out of thin air.
+    pub fn apply_genesis(&mut self, b: &Block) -> Result<(), LedgerError> {
+        let mut next = self.clone();
+        for st in b.transactions.leaves() {
+            let t = &st.transaction;
+            match t.kind {
+                TxKind::Genesis => next.mint(t)?,
+                TxKind::Stake => next.lock_stake(t)?,
+                TxKind::Transfer | TxKind::Coinbase => return Err(LedgerError::UnexpectedTransaction),
+            }
+        }
+        *self = next;
+        Ok(())
+    }
+
+    fn apply_coinbase(&mut self, t: &Transaction, height: u64, expected: Amount) -> Result<(), LedgerError> {
+        let is_plain = t.fee == Amount::ZERO && t.tip == Amount::ZERO && t.change == Amount::ZERO
+            && t.inputs.is_empty() && t.outputs.is_empty();
+        if !is_plain || t.nonce != height {
+            return Err(LedgerError::MalformedCoinbase);
+        }
+        if t.amount != expected {
+            return Err(LedgerError::InvalidCoinbaseAmount{ expected, got: t.amount });
+        }
+        self.mint(t)
+    }
+
+    /// Create the coins of a coinbase or genesis allocation out of thin air
+    fn mint(&mut self, t: &Transaction) -> Result<(), LedgerError> {
+        match self.mode {
+            LedgerMode::Account => self.credit(&t.receiver, t.amount)?,
+            LedgerMode::Utxo => {
+                let outpoint = OutPoint{ tx_hash: t.hash(), index: 0 };
+                self.utxos.insert(outpoint, TxOutput{ receiver: t.receiver.clone(), amount: t.amount });
+            },
+        }
+        Ok(())
+    }
+
+    /// Add the amount of a stake transaction to the stake of its receiver
+    fn lock_stake(&mut self, t: &Transaction) -> Result<(), LedgerError> {
+        let stake = self.stakes.entry(Address::from(&t.receiver)).or_default();
+        *stake = stake.checked_add(t.amount).ok_or(LedgerError::Overflow)?;
+        Ok(())
+    }
+
+    fn check_account_transaction(&self, t: &Transaction) -> Result<(), LedgerError> {
+        if !t.inputs.is_empty() {
+            return Err(LedgerError::UnexpectedInputs);
+        }
+        let required = Self::debit_of(t)?;
+        let balance = self.balance(&t.sender);
+        if required > balance {
+            return Err(LedgerError::Overspend{ balance, required });
+        }
+        Ok(())
+    }
+
+    fn check_utxo_transaction(&self, t: &Transaction) -> Result<(), LedgerError> {
+        if t.inputs.is_empty() {
+            return Err(LedgerError::NoInputs);
+        }
+        let mut inputs = Amount::ZERO;
+        for (ix, input) in t.inputs.iter().enumerate() {
+            if t.inputs[..ix].contains(input) {
+                return Err(LedgerError::DuplicateInput(input.clone()));
+            }
+            let output = self.utxos
+                .get(input)
+                .ok_or_else(|| LedgerError::MissingInput(input.clone()))?;
+            // The transaction is signed by the sender, so they have to own every input
+            if output.receiver != t.sender {
+                return Err(LedgerError::NotOwner(input.clone()));
+            }
+            inputs = inputs.checked_add(output.amount).ok_or(LedgerError::Overflow)?;
+        }
+        let outputs = t.total().ok_or(LedgerError::Overflow)?;
+        if inputs != outputs {
+            return Err(LedgerError::Unbalanced{ inputs, outputs });
+        }
+        Ok(())
+    }
+
+    /// The outputs credited in account mode, the change stays with the sender
+    /// and the amount of a stake is locked instead
+    fn account_outputs(t: &Transaction) -> impl Iterator<Item = TxOutput> + '_ {
+        let receiver = (!t.is_stake()).then(|| TxOutput{ receiver: t.receiver.clone(), amount: t.amount });
+        receiver.into_iter().chain(t.outputs.iter().cloned())
+    }
+
+    /// The amount that is deducted from the senders balance in account mode
+    fn debit_of(t: &Transaction) -> Result<Amount, LedgerError> {
+        let mut debit = t.amount
+            .checked_add(t.fee)
+            .and_then(|a| a.checked_add(t.tip))
+            .ok_or(LedgerError::Overflow)?;
+        for output in t.outputs.iter() {
+            debit = debit.checked_add(output.amount).ok_or(LedgerError::Overflow)?;
+        }
+        Ok(debit)
+    }
+}
+
+#[cfg(test)]
+mod test{
+    // Imports
+    use super::*;
+    use crate::blockchain::BlockHeader;
+    use crate::trader::Trader;
+    use crate::merkletree::MerkleTree;
+    use crate::transaction::{SignedTransaction, DEFAULT_FEE};
+    use crate::test_utils::coins;
+
+    #[test]
+    fn reject_overspend() {
+        let alice = Trader::new();
+        let bob = Trader::new();
+        let mut ledger = Ledger::new(LedgerMode::Account);
+        ledger.credit(&alice.public_key, coins(2)).unwrap();
+
+        // amount + fee + tip must be covered by the balance
+        let mut t = Transaction::new(alice.public_key.clone(), bob.public_key.clone(), coins(1));
+        t.tip(coins(1));
+        let required = coins(2).checked_add(t.fee).unwrap();
+        assert_eq!(ledger.apply_transaction(&t), Err(LedgerError::Overspend{ balance: coins(2), required }));
+        assert_eq!(ledger.balance(&alice.public_key), coins(2));
+
+        let t = Transaction::new(alice.public_key.clone(), bob.public_key.clone(), coins(1));
+        ledger.apply_transaction(&t).unwrap();
+        assert_eq!(ledger.balance(&bob.public_key), coins(1));
+        assert_eq!(ledger.balance(&alice.public_key), coins(1).checked_sub(t.fee).unwrap());
+    }
+
+    #[test]
+    fn reject_block_with_overspend() {
+        let alice = Trader::new();
+        let bob = Trader::new();
+        let mut ledger = Ledger::new(LedgerMode::Account);
+        ledger.credit(&alice.public_key, coins(2)).unwrap();
+
+        // Each transaction is affordable on its own, but not both together
+        let mut transactions = MerkleTree::new();
+        let fees = DEFAULT_FEE.checked_add(DEFAULT_FEE).unwrap();
+        let coinbase = Transaction::coinbase(bob.public_key.clone(), fees, 1);
+        transactions.add(bob.sign(coinbase));
+        for nonce in 0..2 {
+            let mut t = Transaction::new(alice.public_key.clone(), bob.public_key.clone(), coins(1));
+            t.nonce(nonce);
+            transactions.add(alice.sign(t));
+        }
+        let b = Block::new(BlockHeader::new(Vec::new(), 1, 0), transactions);
+        assert!(matches!(ledger.apply_block(&b, Amount::ZERO), Err(LedgerError::Overspend{ .. })));
+        assert_eq!(ledger.balance(&alice.public_key), coins(2));
+        assert_eq!(ledger.balance(&bob.public_key), Amount::ZERO);
+    }
+
+    /// Build a block at height 1 out of the given transactions
+    fn block(transactions: Vec<SignedTransaction>) -> Block {
+        let mut tree = MerkleTree::new();
+        for st in transactions {
+            tree.add(st);
+        }
+        Block::new(BlockHeader::new(Vec::new(), 1, 0), tree)
+    }
+
+    #[test]
+    fn coinbase_rules() {
+        let alice = Trader::new();
+        let miner = Trader::new();
+        let reward = coins(50);
+        let mut ledger = Ledger::new(LedgerMode::Account);
+        ledger.credit(&alice.public_key, coins(2)).unwrap();
+
+        let mut t = Transaction::new(alice.public_key.clone(), miner.public_key.clone(), coins(1));
+        t.tip(coins(1).checked_sub(t.fee).unwrap());
+        let payment = alice.sign(t);
+        let expected = reward.checked_add(coins(1)).unwrap();
+        let coinbase = |amount, height| miner.sign(Transaction::coinbase(miner.public_key.clone(), amount, height));
+
+        // No coinbase at all
+        let b = block(vec![payment.clone()]);
+        assert_eq!(ledger.apply_block(&b, reward), Err(LedgerError::MissingCoinbase));
+
+        // Coinbase that ignores the fees
+        let b = block(vec![coinbase(reward, 1), payment.clone()]);
+        assert_eq!(ledger.apply_block(&b, reward), Err(LedgerError::InvalidCoinbaseAmount{ expected, got: reward }));
+
+        // Coinbase for a different height
+        let b = block(vec![coinbase(expected, 2), payment.clone()]);
+        assert_eq!(ledger.apply_block(&b, reward), Err(LedgerError::MalformedCoinbase));
+
+        // Two coinbases
+        let b = block(vec![coinbase(expected, 1), coinbase(expected, 1), payment.clone()]);
+        assert_eq!(ledger.apply_block(&b, reward), Err(LedgerError::UnexpectedCoinbase));
+
+        let b = block(vec![coinbase(expected, 1), payment]);
+        ledger.apply_block(&b, reward).unwrap();
+        assert_eq!(ledger.balance(&miner.public_key), expected.checked_add(coins(1)).unwrap());
+        assert_eq!(ledger.balance(&alice.public_key), Amount::ZERO);
+    }
+
+    /// Fund `trader` with a single unspent output worth `amount` and return it
+    fn fund(ledger: &mut Ledger, trader: &Trader, amount: Amount) -> OutPoint {
+        ledger.credit(&trader.public_key, amount).unwrap();
+        let mut outputs = ledger.unspent_outputs(&trader.public_key);
+        outputs.retain(|(_, o)| o.amount == amount);
+        outputs.pop().unwrap().0
+    }
+
+    #[test]
+    fn utxo_spend() {
+        let alice = Trader::new();
+        let bob = Trader::new();
+        let carol = Trader::new();
+        let mut ledger = Ledger::new(LedgerMode::Utxo);
+        let input = fund(&mut ledger, &alice, coins(5));
+
+        // Pay 1 coin to bob and 2 coins to carol, the rest minus the fee goes back to alice
+        let mut t = Transaction::new(alice.public_key.clone(), bob.public_key.clone(), coins(1));
+        t.pay(carol.public_key.clone(), coins(2));
+        t.change(coins(2).checked_sub(t.fee).unwrap());
+        t.spend(vec![input.clone()]);
+        ledger.apply_transaction(&t).unwrap();
+
+        assert_eq!(ledger.balance(&bob.public_key), coins(1));
+        assert_eq!(ledger.balance(&carol.public_key), coins(2));
+        assert_eq!(ledger.balance(&alice.public_key), coins(2).checked_sub(t.fee).unwrap());
+
+        // The input can't be spent a second time
+        t.nonce(1);
+        assert_eq!(ledger.check_transaction(&t), Err(LedgerError::MissingInput(input)));
+    }
+
+    #[test]
+    fn lock_stake() {
+        let alice = Trader::new();
+        for mode in [LedgerMode::Account, LedgerMode::Utxo] {
+            let mut ledger = Ledger::new(mode);
+            let mut t = Transaction::stake(alice.public_key.clone(), coins(3));
+            if mode == LedgerMode::Utxo {
+                t.change(coins(2).checked_sub(t.fee).unwrap());
+                t.spend(vec![fund(&mut ledger, &alice, coins(5))]);
+            }
+            else {
+                ledger.credit(&alice.public_key, coins(5)).unwrap();
+            }
+            ledger.apply_transaction(&t).unwrap();
+
+            // The stake is gone from the balance, only the change is left
+            assert_eq!(ledger.stake(&alice.public_key), coins(3));
+            assert_eq!(ledger.balance(&alice.public_key), coins(2).checked_sub(t.fee).unwrap());
+        }
+    }
+
+    #[test]
+    fn utxo_reject_invalid_inputs() {
+        let alice = Trader::new();
+        let bob = Trader::new();
+        let mut ledger = Ledger::new(LedgerMode::Utxo);
+        let alices = fund(&mut ledger, &alice, coins(2));
+        let bobs = fund(&mut ledger, &bob, coins(2));
+
+        let mut t = Transaction::new(alice.public_key.clone(), bob.public_key.clone(), coins(1));
+        assert_eq!(ledger.check_transaction(&t), Err(LedgerError::NoInputs));
+
+        // Spending somebody elses output
+        t.spend(vec![bobs.clone()]);
+        t.change(coins(1).checked_sub(t.fee).unwrap());
+        assert_eq!(ledger.check_transaction(&t), Err(LedgerError::NotOwner(bobs)));
+
+        // Spending the same output twice
+        t.spend(vec![alices.clone(), alices.clone()]);
+        assert_eq!(ledger.check_transaction(&t), Err(LedgerError::DuplicateInput(alices.clone())));
+
+        // Outputs don't match the inputs
+        t.spend(vec![alices]);
+        t.change(coins(1));
+        assert!(matches!(ledger.check_transaction(&t), Err(LedgerError::Unbalanced{ .. })));
+
+        // Account mode doesn't know about inputs
+        let account = Ledger::new(LedgerMode::Account);
+        assert_eq!(account.check_transaction(&t), Err(LedgerError::UnexpectedInputs));
+    }
+
+    #[test]
+    fn reject_replayed_transactions() {
+        let alice = Trader::new();
+        let bob = Trader::new();
+        let mut ledger = Ledger::new(LedgerMode::Account);
+        ledger.credit(&alice.public_key, coins(10)).unwrap();
+
+        let t = Transaction::new(alice.public_key.clone(), bob.public_key.clone(), coins(1));
+        ledger.apply_transaction(&t).unwrap();
+        assert_eq!(ledger.next_nonce(&alice.public_key), 1);
+
+        // Submitting the same transaction again
+        assert_eq!(ledger.apply_transaction(&t), Err(LedgerError::NonceTooLow{ expected: 1, got: 0 }));
+
+        // Skipping a nonce
+        let mut t = t;
+        t.nonce(2);
+        assert_eq!(ledger.apply_transaction(&t), Err(LedgerError::NonceGap{ expected: 1, got: 2 }));
+        t.nonce(1);
+        ledger.apply_transaction(&t).unwrap();
+        assert_eq!(ledger.balance(&bob.public_key), coins(2));
+    }
+}
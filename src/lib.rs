@@ -1,5 +1,19 @@
 pub mod blockchain; // prob bad style since main module is also called blockchain
+pub mod amount;
+pub mod consensus;
+pub mod encoding;
+pub mod error;
+pub mod genesis;
+pub mod index;
+pub mod ledger;
+pub mod mempool;
 pub mod merkletree;
+pub mod miner;
+pub mod policy;
+pub mod orphans;
+pub mod storage;
 pub mod trader;
 pub mod utils;
 pub mod transaction;
+#[cfg(test)]
+mod test_utils;
@@ -1,25 +1,70 @@
 use blockchain::trader::Trader;
 use blockchain::transaction::Transaction;
+use blockchain::amount::Amount;
+use blockchain::genesis::GenesisConfig;
+use blockchain::encoding::{Encode, Decode};
+use blockchain::utils::{to_hex, from_hex};
+use rsa::{RSAPrivateKey, RSAPublicKey};
 use simple_logger::SimpleLogger;
+use std::env;
+use std::fs;
 
 fn main() {
     // Setup Logger
     SimpleLogger::new().init().unwrap();
 
+    // All traders have to share the same genesis block, optionally loaded from the file given
+    // as the first argument. The key of the first trader is read from the file given as the
+    // second argument, the config has to fund it. Without a config, it is funded by a local one.
+    let (genesis, k1) = match env::args().nth(1) {
+        Some(path) => {
+            let genesis = GenesisConfig::from_file(path).unwrap();
+            let key = match env::args().nth(2) {
+                Some(path) => load_or_create_key(&path),
+                None => Trader::generate_key(),
+            };
+            (genesis, key)
+        },
+        None => {
+            let key = Trader::generate_key();
+            let mut genesis = GenesisConfig::default();
+            genesis.allocations.push((RSAPublicKey::from(&key), "100".parse().unwrap()));
+            (genesis, key)
+        },
+    };
+
     // Create core entities
-    let mut t1 = Trader::new();
-    let mut t2 = Trader::new();
+    let t1 = Trader::with_key(k1, &genesis).unwrap();
+    let mut t2 = Trader::with_key(Trader::generate_key(), &genesis).unwrap();
     let m1 = t1.spawn_miner_thread();
 
     // Create links for p2p network
-    t1.link(&mut t2);
-    t1.register_miner(&m1);
-    t2.register_miner(&m1);
+    t1.link(&mut t2).unwrap();
+    t1.register_miner(&m1).unwrap();
+    t2.register_miner(&m1).unwrap();
 
-    let t = Transaction::new(t1.public_key.clone(), t2.public_key.clone(), 1.0);
+    let mut t = Transaction::new(t1.public_key.clone(), t2.public_key.clone(), "1.0".parse::<Amount>().unwrap());
+    t.nonce(t1.next_nonce());
     let st = t1.sign(t);
-    t1.broadcast(&st);
+    if let Err(e) = t1.broadcast(&st) {
+        eprintln!("Failed to broadcast transaction: {}", e);
+    }
 
     // Wait for the user to stop execution (Ctrl+C)
-    loop{}
+    loop{
+        std::thread::park();
+    }
+}
+
+/// Read the private key stored in `path`, or create a new one there if the file doesn't exist
+fn load_or_create_key(path: &str) -> RSAPrivateKey {
+    if let Ok(hex) = fs::read_to_string(path) {
+        let bytes = from_hex(hex.trim()).expect("Key file is not hex encoded");
+        return RSAPrivateKey::from_bytes(&bytes).expect("Invalid key file");
+    }
+    let key = Trader::generate_key();
+    fs::write(path, to_hex(&key.to_bytes())).expect("Failed to write the key file");
+    println!("Created {}, fund it in the genesis config with:", path);
+    println!("allocation = {} 100", to_hex(&RSAPublicKey::from(&key).to_bytes()));
+    key
 }
@@ -0,0 +1,348 @@
+use crate::amount::Amount;
+use crate::blockchain::Block;
+use crate::encoding::Encode;
+use crate::ledger::{Address, Ledger, LedgerError};
+use crate::transaction::{SignedTransaction, TxError};
+use std::cmp::{Ordering, Reverse};
+use std::collections::{BinaryHeap, HashMap};
+use std::fmt;
+
+/// Default number of bytes all pending transactions may take up together
+pub const DEFAULT_MAX_MEMPOOL_SIZE: usize = 1_000_000;
+
+#[derive(Debug, Clone)]
+struct Entry{
+    transaction: SignedTransaction,
+    /// Encoded size in bytes
+    size: usize,
+    /// Fee plus tip, which is what the miner earns by including the transaction
+    reward: Amount,
+    /// Order of arrival, breaks ties between equal fee rates
+    sequence: u64,
+}
+
+impl Entry{
+    /// Compare the reward per byte of two entries, the better paying one is greater
+    fn fee_rate_cmp(&self, other: &Entry) -> Ordering {
+        let a = self.reward.base_units() as u128 * other.size as u128;
+        let b = other.reward.base_units() as u128 * self.size as u128;
+        a.cmp(&b).then(other.sequence.cmp(&self.sequence))
+    }
+}
+
+/// The order in which pending transactions are considered for a block template
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Priority{
+    /// Highest fee plus tip per byte first
+    #[default]
+    FeeRate,
+    /// Highest tip first, regardless of the size of the transaction
+    Tip,
+    /// Oldest first
+    Arrival,
+}
+
+impl Priority{
+    /// Compare two entries, the one that should be picked first is greater
+    fn cmp(self, a: &Entry, b: &Entry) -> Ordering {
+        let arrival = b.sequence.cmp(&a.sequence);
+        match self {
+            Priority::FeeRate => a.fee_rate_cmp(b),
+            Priority::Tip => a.transaction.transaction.tip.cmp(&b.transaction.transaction.tip).then(arrival),
+            Priority::Arrival => arrival,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MempoolError{
+    /// The transaction is already pending
+    AlreadyKnown,
+    /// The transaction is invalid on its own
+    Invalid(TxError),
+    /// The transaction can't be applied on top of the ledger and the pending transactions
+    Rejected(LedgerError),
+    /// The pool is full of transactions that pay more per byte
+    Full,
+}
+
+impl fmt::Display for MempoolError{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MempoolError::AlreadyKnown => write!(f, "transaction is already pending"),
+            MempoolError::Invalid(e) => write!(f, "{}", e),
+            MempoolError::Rejected(e) => write!(f, "{}", e),
+            MempoolError::Full => write!(f, "mempool is full and the fee rate is too low"),
+        }
+    }
+}
+
+impl std::error::Error for MempoolError {}
+
+impl From<TxError> for MempoolError{
+    fn from(e: TxError) -> Self {
+        MempoolError::Invalid(e)
+    }
+}
+
+impl From<LedgerError> for MempoolError{
+    fn from(e: LedgerError) -> Self {
+        MempoolError::Rejected(e)
+    }
+}
+
+/// Transactions that were broadcast but are not part of a block yet, keyed by their hash
+#[derive(Debug, Clone)]
+pub struct Mempool{
+    entries: HashMap<Vec<u8>, Entry>,
+    /// Total size of all entries
+    size: usize,
+    max_size: usize,
+    next_sequence: u64,
+}
+
+impl Default for Mempool{
+    fn default() -> Self {
+        Self::new(DEFAULT_MAX_MEMPOOL_SIZE)
+    }
+}
+
+impl Mempool{
+    /// A pool whose transactions take up at most `max_size` bytes
+    pub fn new(max_size: usize) -> Self {
+        Mempool{
+            entries: HashMap::new(),
+            size: 0,
+            max_size,
+            next_sequence: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Total encoded size of all pending transactions
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    pub fn contains(&self, hash: &[u8]) -> bool {
+        self.entries.contains_key(hash)
+    }
+
+    /// Add a transaction after checking its signature and that it can be applied to `ledger`
+    /// once the pending transactions of the same sender are applied.
+    /// If the pool grows too large, the transactions paying the least per byte are evicted.
+    pub fn insert(&mut self, st: SignedTransaction, ledger: &Ledger) -> Result<(), MempoolError> {
+        let hash = st.transaction.hash();
+        if self.contains(&hash) {
+            return Err(MempoolError::AlreadyKnown);
+        }
+        st.verify()?;
+
+        // Transactions of the same sender may build on each other, e.g. with increasing nonces
+        let mut pending = ledger.clone();
+        let mut previous: Vec<&Entry> = self.entries
+            .values()
+            .filter(|e| e.transaction.transaction.sender == st.transaction.sender)
+            .collect();
+        previous.sort_by_key(|e| e.transaction.transaction.nonce);
+        for entry in previous {
+            // Entries that no longer apply are skipped, they are never part of a template
+            let _ = pending.apply_transaction(&entry.transaction.transaction);
+        }
+        pending.check_transaction(&st.transaction)?;
+
+        let t = &st.transaction;
+        let reward = t.fee.checked_add(t.tip).ok_or(TxError::Overflow)?;
+        let size = st.to_bytes().len();
+        let sequence = self.next_sequence;
+        self.next_sequence += 1;
+        self.entries.insert(hash.clone(), Entry{ transaction: st, size, reward, sequence });
+        self.size += size;
+
+        while self.size > self.max_size {
+            let lowest = self.entries
+                .iter()
+                .min_by(|(_, a), (_, b)| a.fee_rate_cmp(b))
+                .map(|(hash, _)| hash.clone())
+                .expect("A pool above its size limit is never empty");
+            self.remove(&lowest);
+            if lowest == hash {
+                return Err(MempoolError::Full);
+            }
+        }
+        Ok(())
+    }
+
+    pub fn remove(&mut self, hash: &[u8]) -> Option<SignedTransaction> {
+        let entry = self.entries.remove(hash)?;
+        self.size -= entry.size;
+        Some(entry.transaction)
+    }
+
+    /// Drop every transaction that is included in `block`
+    pub fn remove_block(&mut self, block: &Block) {
+        for st in block.transactions.leaves() {
+            self.remove(&st.transaction.hash());
+        }
+    }
+
+    /// Drop every transaction whose nonce was already used according to `ledger`
+    pub fn prune(&mut self, ledger: &Ledger) {
+        let stale: Vec<Vec<u8>> = self.entries
+            .iter()
+            .filter(|(_, e)| e.transaction.transaction.nonce < ledger.next_nonce(&e.transaction.transaction.sender))
+            .map(|(hash, _)| hash.clone())
+            .collect();
+        for hash in stale {
+            self.remove(&hash);
+        }
+    }
+
+    /// The transactions for the next block, taking up at most `max_size` bytes.
+    /// Transactions are picked in the order given by `priority`, as long as they can be applied
+    /// to `ledger` in that order. A transaction that waits for an earlier nonce of its sender is
+    /// picked once that one is included, any other transaction that fails is not tried again.
+    pub fn template(&self, ledger: &Ledger, max_size: usize, priority: Priority) -> Vec<SignedTransaction> {
+        let mut candidates: Vec<&Entry> = self.entries.values().collect();
+        candidates.sort_by(|a, b| priority.cmp(b, a));
+
+        // Positions in `candidates`, the smallest one is picked next
+        let mut ready: BinaryHeap<Reverse<usize>> = (0..candidates.len()).map(Reverse).collect();
+        let mut waiting: HashMap<(Address, u64), usize> = HashMap::new();
+        let mut ledger = ledger.clone();
+        let mut selected = Vec::new();
+        let mut size = 0;
+        while let Some(Reverse(ix)) = ready.pop() {
+            let e = candidates[ix];
+            let t = &e.transaction.transaction;
+            if size + e.size > max_size {
+                continue;
+            }
+            match ledger.apply_transaction(t) {
+                Ok(()) => {
+                    size += e.size;
+                    selected.push(e.transaction.clone());
+                    if let Some(next) = waiting.remove(&(Address::from(&t.sender), t.nonce + 1)) {
+                        ready.push(Reverse(next));
+                    }
+                },
+                Err(LedgerError::NonceGap{ .. }) => {
+                    waiting.entry((Address::from(&t.sender), t.nonce)).or_insert(ix);
+                },
+                Err(_) => {},
+            }
+        }
+        selected
+    }
+}
+
+#[cfg(test)]
+mod test{
+    // Imports
+    use super::*;
+    use crate::ledger::LedgerMode;
+    use crate::trader::Trader;
+    use crate::transaction::Transaction;
+    use crate::test_utils::coins;
+
+    fn payment(from: &Trader, to: &Trader, nonce: u64, tip: Amount) -> SignedTransaction {
+        let mut t = Transaction::new(from.public_key.clone(), to.public_key.clone(), coins(1));
+        t.nonce(nonce);
+        t.tip(tip);
+        from.sign(t)
+    }
+
+    #[test]
+    fn fee_priority() {
+        let alice = Trader::new();
+        let bob = Trader::new();
+        let mut ledger = Ledger::new(LedgerMode::Account);
+        ledger.credit(&alice.public_key, coins(10)).unwrap();
+        ledger.credit(&bob.public_key, coins(10)).unwrap();
+        let mut pool = Mempool::default();
+
+        let cheap = payment(&alice, &bob, 0, Amount::ZERO);
+        let chained = payment(&alice, &bob, 1, coins(2));
+        let rich = payment(&bob, &alice, 0, coins(1));
+        pool.insert(cheap.clone(), &ledger).unwrap();
+        assert_eq!(pool.insert(cheap.clone(), &ledger), Err(MempoolError::AlreadyKnown));
+        pool.insert(chained.clone(), &ledger).unwrap();
+        pool.insert(rich.clone(), &ledger).unwrap();
+
+        // A gap in the nonces or a bad signature is rejected
+        let gap = payment(&alice, &bob, 5, Amount::ZERO);
+        assert!(matches!(pool.insert(gap, &ledger), Err(MempoolError::Rejected(LedgerError::NonceGap{ .. }))));
+        let mut forged = payment(&bob, &alice, 1, Amount::ZERO);
+        forged.transaction.amount = coins(5);
+        assert_eq!(pool.insert(forged, &ledger), Err(MempoolError::Invalid(TxError::InvalidSignature)));
+
+        // The chained transaction pays the most, but has to wait for its predecessor
+        let hashes = |txs: Vec<SignedTransaction>| txs.iter().map(|st| st.transaction.hash()).collect::<Vec<_>>();
+        let template = pool.template(&ledger, usize::MAX, Priority::FeeRate);
+        assert_eq!(hashes(template), hashes(vec![rich.clone(), cheap.clone(), chained.clone()]));
+        let template = pool.template(&ledger, usize::MAX, Priority::Arrival);
+        assert_eq!(hashes(template), hashes(vec![cheap.clone(), chained.clone(), rich.clone()]));
+
+        // Only the best paying transaction fits
+        let template = pool.template(&ledger, pool.size() / 2, Priority::FeeRate);
+        assert_eq!(hashes(template), hashes(vec![rich.clone()]));
+
+        // Confirmed transactions are dropped, including ones from blocks the pool never saw
+        ledger.apply_transaction(&cheap.transaction).unwrap();
+        ledger.apply_transaction(&rich.transaction).unwrap();
+        pool.prune(&ledger);
+        assert_eq!(pool.len(), 1);
+        assert_eq!(hashes(pool.template(&ledger, usize::MAX, Priority::FeeRate)), hashes(vec![chained]));
+    }
+
+    #[test]
+    fn template_skips_failed_transactions() {
+        let alice = Trader::new();
+        let bob = Trader::new();
+        let mut ledger = Ledger::new(LedgerMode::Account);
+        ledger.credit(&alice.public_key, coins(10)).unwrap();
+        ledger.credit(&bob.public_key, coins(10)).unwrap();
+        let mut pool = Mempool::default();
+        for nonce in 0..3 {
+            pool.insert(payment(&alice, &bob, nonce, coins(nonce)), &ledger).unwrap();
+        }
+        let rich = payment(&bob, &alice, 0, Amount::ZERO);
+        pool.insert(rich.clone(), &ledger).unwrap();
+
+        // Alice can't afford her first payment anymore, so the ones after it wait forever
+        let mut poor = Ledger::new(LedgerMode::Account);
+        poor.credit(&bob.public_key, coins(10)).unwrap();
+        let template = pool.template(&poor, usize::MAX, Priority::FeeRate);
+        assert_eq!(template.len(), 1);
+        assert_eq!(template[0].transaction.hash(), rich.transaction.hash());
+        assert_eq!(pool.template(&ledger, usize::MAX, Priority::FeeRate).len(), 4);
+    }
+
+    #[test]
+    fn evict_lowest_fee_rate() {
+        let alice = Trader::new();
+        let bob = Trader::new();
+        let mut ledger = Ledger::new(LedgerMode::Account);
+        ledger.credit(&alice.public_key, coins(10)).unwrap();
+        ledger.credit(&bob.public_key, coins(10)).unwrap();
+
+        let cheap = payment(&alice, &bob, 0, Amount::ZERO);
+        let rich = payment(&bob, &alice, 0, coins(1));
+        let mut pool = Mempool::new(cheap.to_bytes().len() + 1);
+        pool.insert(cheap.clone(), &ledger).unwrap();
+
+        // The better paying transaction pushes the cheap one out, but not the other way around
+        pool.insert(rich.clone(), &ledger).unwrap();
+        assert!(!pool.contains(&cheap.transaction.hash()));
+        assert_eq!(pool.insert(cheap, &ledger), Err(MempoolError::Full));
+        assert!(pool.contains(&rich.transaction.hash()));
+        assert_eq!(pool.size(), rich.to_bytes().len());
+    }
+}
@@ -1,11 +1,35 @@
 // https://codereview.stackexchange.com/questions/133209/binary-tree-implementation-in-rust
 // All hail the Shepmaster!
-use crate::utils::sha256_digest;
-use std::hash::{Hash, Hasher};
+use crate::utils::sha256;
+use crate::encoding::{Encode, Decode, Reader, DecodeError, encode_seq, decode_seq};
+use std::fmt;
 use std::fmt::Debug;
 
+/// Domain separation prefixes, so a leaf can never be mistaken for an inner node
+const LEAF_PREFIX: u8 = 0x00;
+const NODE_PREFIX: u8 = 0x01;
+
 type Link<T> = Option<Box<Node<T>>>;
 
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MerkleError{
+    /// The stored hash of an inner node doesn't match the hash of its children
+    HashMismatch,
+    /// The root of the tree is a leaf instead of an inner node
+    LeafRoot,
+}
+
+impl fmt::Display for MerkleError{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MerkleError::HashMismatch => write!(f, "merkle tree hashes are inconsistent"),
+            MerkleError::LeafRoot => write!(f, "merkle root cannot be a leaf node"),
+        }
+    }
+}
+
+impl std::error::Error for MerkleError {}
+
 #[derive(Clone, Debug)]
 pub enum Node<T>
 where T: Clone{
@@ -22,7 +46,13 @@ pub struct MerkleTree<T: Clone>{
     pub root: Box<Node<T>>,
 }
 
-impl<T: Clone + Hash + Debug> MerkleTree<T>{
+impl<T: Clone + Encode + Debug> Default for MerkleTree<T>{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T: Clone + Encode + Debug> MerkleTree<T>{
     pub fn new() -> Self {
         MerkleTree{ 
             root: Box::new(Node::HashNode{
@@ -60,14 +90,14 @@ impl<T: Clone + Hash + Debug> MerkleTree<T>{
                 hash: Vec::new(),
             };
             new_root.set_hash();
-            self.root = Box::new(new_root);
+            *self.root = new_root;
         }
     }
 
-    pub fn get_root_hash(&self) -> &Vec<u8> {
+    pub fn get_root_hash(&self) -> Result<&Vec<u8>, MerkleError> {
         match *self.root {
-            Node::HashNode{left: _, right: _, ref hash} => hash,
-            Node::LeafNode(_) => panic!("Merkle root node cannot be leafnode!"),
+            Node::HashNode{left: _, right: _, ref hash} => Ok(hash),
+            Node::LeafNode(_) => Err(MerkleError::LeafRoot),
         }
     }
 
@@ -88,40 +118,64 @@ impl<T: Clone + Hash + Debug> MerkleTree<T>{
         self.root.len()
     }
 
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Return references to all leaf values, from left to right
+    pub fn leaves(&self) -> Vec<&T> {
+        let mut out = Vec::new();
+        self.root.collect_leaves(&mut out);
+        out
+    }
+
     pub fn get_depth(&self) -> i32 {
         self.root.get_depth()
     }
 
-    pub fn is_valid(&self) -> bool {
-        self.root.is_valid()
+    /// Check that every stored hash matches the contents of the tree
+    pub fn verify(&self) -> Result<(), MerkleError> {
+        if let Node::LeafNode(_) = *self.root {
+            return Err(MerkleError::LeafRoot);
+        }
+        self.root.verify()
+    }
+}
+
+/// A tree is encoded as the list of its leaves, the inner nodes are rebuilt on decoding
+impl<T: Clone + Debug + Encode> Encode for MerkleTree<T>{
+    fn encode(&self, buf: &mut Vec<u8>) {
+        encode_seq(self.leaves().into_iter(), buf);
     }
 }
 
-impl<T: Clone + Debug + Hash> Hash for MerkleTree<T>{
-    fn hash<H: Hasher>(&self, state: &mut H) {
-            self.get_root_hash().hash(state);
+impl<T: Clone + Debug + Encode + Decode> Decode for MerkleTree<T>{
+    fn decode(reader: &mut Reader) -> Result<Self, DecodeError> {
+        let mut tree = MerkleTree::new();
+        for leaf in decode_seq(reader)? {
+            tree.add(leaf);
+        }
+        Ok(tree)
     }
 }
 
-impl<T: Clone + Hash + Debug> Node<T>{
+impl<T: Clone + Encode + Debug> Node<T>{
     // Verify the hashes within the subtree where root is self
-    pub fn is_valid(&self) -> bool {
+    pub fn verify(&self) -> Result<(), MerkleError> {
         match self{
             Node::HashNode{left, right, hash} => {
-                let left_is_valid = match left {
-                    Some(n) => n.is_valid(),
-                    None => true,
-                };
-                let right_is_valid = match right {
-                    Some(n) => n.is_valid(),
-                    None => true,
-                };
-                let target = self.calc_hash();
-                let i_am_valid = target == *hash;
-
-                left_is_valid && right_is_valid && i_am_valid
+                if let Some(n) = left {
+                    n.verify()?;
+                }
+                if let Some(n) = right {
+                    n.verify()?;
+                }
+                if self.calc_hash() != *hash {
+                    return Err(MerkleError::HashMismatch);
+                }
+                Ok(())
             },
-            Node::LeafNode(_) => {true},
+            Node::LeafNode(_) => Ok(()),
         }
     }
     
@@ -143,13 +197,11 @@ impl<T: Clone + Hash + Debug> Node<T>{
                 if !rightnode.is_full(){
                     rightnode.add(c);
                     self.set_hash();
-                    return
                 }
             }
             else{
                 *right = Some(Box::new(c));
                 self.set_hash();
-                return
             }
         }
     }
@@ -160,7 +212,9 @@ impl<T: Clone + Hash + Debug> Node<T>{
                 hash.to_vec()
             },
             Node::LeafNode(content) => {
-                sha256_digest(&content)
+                let mut bytes = vec![LEAF_PREFIX];
+                content.encode(&mut bytes);
+                sha256(&bytes)
             },
         }
     }
@@ -172,8 +226,9 @@ impl<T: Clone + Hash + Debug> Node<T>{
         }
     }
 
+    /// Compute the hash of the node from its children, or from its content for leaves
     pub fn calc_hash(&self) -> Vec<u8>{
-        if let Node::HashNode{left, right, hash} = self {
+        if let Node::HashNode{left, right, ..} = self {
             let mut combined = Vec::new();
 
             if let Some(node) = left {
@@ -182,17 +237,17 @@ impl<T: Clone + Hash + Debug> Node<T>{
             if let Some(node) = right {
                 combined.extend(&node.get_hash());
             }
-            // extend byte vector if necessary
-            if combined.len() == 0 {
+            // A lone child is hashed on its own instead of being paired with itself,
+            // otherwise `[a, b, c]` and `[a, b, c, c]` would share the same root
+            if combined.is_empty() {
                 combined = vec![0, 64];
             }
-            else if combined.len() == 32 { 
-                combined.extend(&combined.clone());
-            }
-            sha256_digest(&combined)
+            let mut bytes = vec![NODE_PREFIX];
+            bytes.extend(combined);
+            sha256(&bytes)
         }
         else{
-            panic!("Calling .calc_hash() on a LeafNode doesnt make sense");
+            self.get_hash()
         }
     }
     
@@ -213,6 +268,10 @@ impl<T: Clone + Hash + Debug> Node<T>{
         }
     }
 
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
     /// Get the number of leaf nodes in the subgraph
     pub fn len(&self) -> i32 {
         if let Node::HashNode{left, right, ..} = self{
@@ -244,6 +303,21 @@ impl<T: Clone + Hash + Debug> Node<T>{
         }
     }
 
+    /// Push all leaf values in the subtree onto `out`, from left to right
+    pub fn collect_leaves<'a>(&'a self, out: &mut Vec<&'a T>) {
+        match self {
+            Node::HashNode{left, right, ..} => {
+                if let Some(node) = left {
+                    node.collect_leaves(out);
+                }
+                if let Some(node) = right {
+                    node.collect_leaves(out);
+                }
+            },
+            Node::LeafNode(value) => out.push(value),
+        }
+    }
+
     /// preorder traversing(debugging only)
     pub fn traverse_preorder(&self, out: &mut Vec<i32>){
         match self {
@@ -307,18 +381,52 @@ mod test{
         // Fill the tree
         for index in 1..10 {
             tree.add(index);
-            assert!(tree.is_valid());
+            assert_eq!(tree.verify(), Ok(()));
         }
 
         // Invalidate the tree by modifying the root node's hash
-        if let Node::HashNode{ref left, ref right, ref mut hash} = *tree.root {
+        if let Node::HashNode{ref mut hash, ..} = *tree.root {
             let new_hash = vec![1, 2, 3, 4];
             *hash = new_hash;
         }
         else {
             panic!("Root node is not a HashNode!");
         }
-        assert!(!tree.is_valid());
+        assert_eq!(tree.verify(), Err(MerkleError::HashMismatch));
+
+        // A single leaf is not a valid tree
+        *tree.root = Node::LeafNode(1);
+        assert_eq!(tree.get_root_hash(), Err(MerkleError::LeafRoot));
+        assert_eq!(tree.verify(), Err(MerkleError::LeafRoot));
+    }
+
+    #[test]
+    fn deterministic_root_hash() {
+        let mut a = MerkleTree::new();
+        let mut b = MerkleTree::new();
+        for index in 1..10 {
+            a.add(index);
+            b.add(index);
+        }
+        assert_eq!(a.get_root_hash(), b.get_root_hash());
+        assert_eq!(a.get_root_hash().unwrap().len(), 32);
+
+        b.add(10);
+        assert_ne!(a.get_root_hash(), b.get_root_hash());
+    }
+
+    #[test]
+    fn duplicated_leaves_change_the_root() {
+        let tree = |leaves: &[i32]| {
+            let mut tree = MerkleTree::new();
+            for leaf in leaves {
+                tree.add(*leaf);
+            }
+            tree.get_root_hash().unwrap().clone()
+        };
+        assert_ne!(tree(&[1]), tree(&[1, 1]));
+        assert_ne!(tree(&[1, 2, 3]), tree(&[1, 2, 3, 3]));
+        assert_ne!(tree(&[1, 2, 3, 4, 5, 6]), tree(&[1, 2, 3, 4, 5, 6, 5, 6]));
     }
 
 
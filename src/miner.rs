@@ -0,0 +1,227 @@
+use crate::amount::Amount;
+use crate::blockchain::BlockHeader;
+use crate::transaction::SignedTransaction;
+use std::ops::RangeInclusive;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering as AtomicOrdering};
+use std::thread;
+use std::time::Duration;
+
+/// Number of nonces a worker tries between two checks whether it should stop
+pub const CHECK_INTERVAL: u64 = 10_000;
+
+/// Time between two checks whether the block is still worth mining
+pub const POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+/// Why a miner stopped working on a block before solving it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Interrupt{
+    /// Another block extended the chain, the block would be stale
+    NewTip,
+    /// Transactions arrived that pay more than the ones in the block
+    BetterTransactions,
+}
+
+/// What a miner has done so far
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MinerStats{
+    /// Solved blocks that extended the active chain
+    pub blocks: u64,
+    /// Solved blocks that didn't extend the active chain, because another block was found first
+    pub stale_blocks: u64,
+    /// Blocks that were abandoned before they were solved, because the chain got a new tip
+    pub abandoned: u64,
+    /// Blocks that were rebuilt before they were solved, to include better paying transactions
+    pub rebuilt: u64,
+    /// Number of hashes computed
+    pub hashes: u64,
+    /// Time spent searching nonces
+    pub mining_time: Duration,
+}
+
+impl MinerStats{
+    /// Average number of hashes per second
+    pub fn hashrate(&self) -> f64 {
+        if self.mining_time.is_zero() {
+            return 0.0;
+        }
+        self.hashes as f64 / self.mining_time.as_secs_f64()
+    }
+
+    /// Count an interrupted search
+    pub fn interrupted(&mut self, reason: Interrupt) {
+        match reason {
+            Interrupt::NewTip => self.abandoned += 1,
+            Interrupt::BetterTransactions => self.rebuilt += 1,
+        }
+    }
+}
+
+/// Sum of fees and tips the miner collects by including `transactions`
+pub fn collected_fees(transactions: &[SignedTransaction]) -> Amount {
+    transactions
+        .iter()
+        .try_fold(Amount::ZERO, |sum, st| sum.checked_add(st.transaction.fee)?.checked_add(st.transaction.tip))
+        .expect("Fees of valid transactions cannot overflow")
+}
+
+/// A proof-of-work search that splits the nonces between several worker threads
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MiningEngine{
+    /// Number of threads searching nonces in parallel
+    pub workers: usize,
+    /// The highest nonce tried per timestamp, the nonces up to it are split evenly between the workers.
+    /// Once all of them are tried, the timestamp is increased and the search starts over.
+    pub max_nonce: u64,
+}
+
+/// One worker per CPU core, searching every possible nonce
+impl Default for MiningEngine{
+    fn default() -> Self {
+        MiningEngine{
+            workers: thread::available_parallelism().map(|n| n.get()).unwrap_or(1),
+            max_nonce: u64::MAX,
+        }
+    }
+}
+
+impl MiningEngine{
+    pub fn new(workers: usize) -> Self {
+        MiningEngine{ workers, ..MiningEngine::default() }
+    }
+
+    /// Search a nonce that solves the proof-of-work of `header`, counting the tries in `hashes`.
+    /// While the workers are busy, `check` is regularly asked whether the block is still worth mining.
+    /// All workers stop as soon as one of them finds a solution or `check` interrupts the search.
+    pub fn search(&self, header: &mut BlockHeader, hashes: &mut u64, mut check: impl FnMut() -> Option<Interrupt>) -> Result<(), Interrupt> {
+        // Counted in u128, since every u64 is a valid nonce
+        let nonces = self.max_nonce as u128 + 1;
+        let workers = (self.workers.max(1) as u128).min(nonces);
+        let chunk = nonces / workers;
+        loop {
+            let stop = AtomicBool::new(false);
+            let counter = AtomicU64::new(0);
+            let solution = Mutex::new(None);
+            let mut interrupt = None;
+            thread::scope(|scope| {
+                let handles: Vec<_> = (0..workers).map(|ix| {
+                    let first = (ix * chunk) as u64;
+                    // The last worker also takes the remainder
+                    let last = if ix + 1 == workers { self.max_nonce } else { ((ix + 1) * chunk - 1) as u64 };
+                    let candidate = header.clone();
+                    let (stop, counter, solution) = (&stop, &counter, &solution);
+                    scope.spawn(move || search_range(candidate, first..=last, stop, counter, solution))
+                }).collect();
+
+                while !handles.iter().all(|h| h.is_finished()) {
+                    thread::sleep(POLL_INTERVAL);
+                    if stop.load(AtomicOrdering::Relaxed) {
+                        break;
+                    }
+                    if let Some(reason) = check() {
+                        interrupt = Some(reason);
+                        stop.store(true, AtomicOrdering::Relaxed);
+                    }
+                }
+            });
+            *hashes += counter.into_inner();
+
+            if let Some(nonce) = solution.into_inner().unwrap_or(None) {
+                header.nonce = nonce;
+                return Ok(());
+            }
+            if let Some(reason) = interrupt {
+                return Err(reason);
+            }
+            // Every nonce was tried, the next timestamp gives a whole new set of hashes
+            header.timestamp += 1;
+        }
+    }
+}
+
+/// Try every nonce in `nonces` until one solves the header or `stop` is set
+fn search_range(mut header: BlockHeader, nonces: RangeInclusive<u64>, stop: &AtomicBool, counter: &AtomicU64, solution: &Mutex<Option<u64>>) {
+    let (mut start, last) = nonces.into_inner();
+    while !stop.load(AtomicOrdering::Relaxed) {
+        let end = last.min(start.saturating_add(CHECK_INTERVAL - 1));
+        for nonce in start..=end {
+            header.nonce = nonce;
+            if header.meets_difficulty() {
+                counter.fetch_add(nonce - start + 1, AtomicOrdering::Relaxed);
+                if let Ok(mut solution) = solution.lock() {
+                    solution.get_or_insert(nonce);
+                }
+                stop.store(true, AtomicOrdering::Relaxed);
+                return;
+            }
+        }
+        counter.fetch_add(end - start + 1, AtomicOrdering::Relaxed);
+        if end == last {
+            return;
+        }
+        start = end + 1;
+    }
+}
+
+#[cfg(test)]
+mod test{
+    // Imports
+    use super::*;
+    use crate::blockchain::{Blockchain, MAX_DIFFICULTY};
+
+    #[test]
+    fn interrupt_search() {
+        let mut header = Blockchain::default().blocks[0].header.clone();
+        header.difficulty = MAX_DIFFICULTY;
+        let mut hashes = 0;
+        let mut checks = 0;
+        let result = MiningEngine::new(2).search(&mut header, &mut hashes, || {
+            checks += 1;
+            if checks == 2 { Some(Interrupt::NewTip) } else { None }
+        });
+        assert_eq!(result, Err(Interrupt::NewTip));
+        assert_eq!(checks, 2);
+        assert!(hashes > 0);
+    }
+
+    #[test]
+    fn parallel_search() {
+        let mut header = Blockchain::default().blocks[0].header.clone();
+        header.difficulty = 12;
+        let mut hashes = 0;
+        assert_eq!(MiningEngine::new(4).search(&mut header, &mut hashes, || None), Ok(()));
+        assert!(header.meets_difficulty());
+        assert!(hashes > 0);
+
+        // With only a few nonces per timestamp, the timestamp has to roll over
+        let mut header = Blockchain::default().blocks[0].header.clone();
+        header.difficulty = 8;
+        let engine = MiningEngine{ workers: 3, max_nonce: 3 };
+        let unsolved = (0..=3).all(|nonce| {
+            header.nonce = nonce;
+            !header.meets_difficulty()
+        });
+        assert!(unsolved);
+        assert_eq!(engine.search(&mut header, &mut hashes, || None), Ok(()));
+        assert!(header.meets_difficulty());
+        assert!(header.nonce <= 3);
+        assert!(header.timestamp > 0);
+    }
+
+    #[test]
+    fn search_last_nonce() {
+        let mut header = Blockchain::default().blocks[0].header.clone();
+        header.difficulty = 0;
+        let (stop, counter, solution) = (AtomicBool::new(false), AtomicU64::new(0), Mutex::new(None));
+        search_range(header.clone(), u64::MAX..=u64::MAX, &stop, &counter, &solution);
+        assert_eq!(*solution.lock().unwrap(), Some(u64::MAX));
+        assert_eq!(counter.into_inner(), 1);
+
+        // The range ends at the largest nonce without overflowing
+        header.difficulty = MAX_DIFFICULTY;
+        let (stop, counter, solution) = (AtomicBool::new(false), AtomicU64::new(0), Mutex::new(None));
+        search_range(header, u64::MAX - 9..=u64::MAX, &stop, &counter, &solution);
+        assert_eq!(*solution.lock().unwrap(), None);
+        assert_eq!(counter.into_inner(), 10);
+    }
+}
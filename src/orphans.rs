@@ -0,0 +1,215 @@
+use crate::blockchain::Block;
+use crate::utils::sha256_digest;
+use log::{info, warn};
+use std::collections::HashMap;
+
+/// Default number of orphans that are kept at most
+pub const DEFAULT_MAX_ORPHANS: usize = 100;
+
+/// Default number of seconds an orphan is kept before it is dropped
+pub const DEFAULT_MAX_ORPHAN_AGE: u64 = 600;
+
+#[derive(Debug, Clone)]
+struct Orphan{
+    block: Block,
+    hash: Vec<u8>,
+    /// Hash of the whole encoded block, which tells apart copies that only share the header
+    id: Vec<u8>,
+    /// Local unix time at which the block arrived
+    received: u64,
+}
+
+/// Counters describing what happened to the blocks passing through the pool
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct OrphanStats{
+    /// Blocks that were stored because their parent was unknown
+    pub added: u64,
+    /// Blocks that were handed back once their parent arrived
+    pub connected: u64,
+    /// Blocks that were dropped because they were older than the age limit
+    pub expired: u64,
+    /// Blocks that were dropped to make room for newer ones
+    pub evicted: u64,
+}
+
+/// Blocks that arrived before their parent, keyed by the hash of the missing parent
+#[derive(Debug, Clone)]
+pub struct OrphanPool{
+    orphans: HashMap<Vec<u8>, Vec<Orphan>>,
+    len: usize,
+    max_orphans: usize,
+    max_age: u64,
+    stats: OrphanStats,
+}
+
+impl Default for OrphanPool{
+    fn default() -> Self {
+        Self::new(DEFAULT_MAX_ORPHANS, DEFAULT_MAX_ORPHAN_AGE)
+    }
+}
+
+impl OrphanPool{
+    /// A pool holding at most `max_orphans` blocks, each for at most `max_age` seconds
+    pub fn new(max_orphans: usize, max_age: u64) -> Self {
+        OrphanPool{
+            orphans: HashMap::new(),
+            len: 0,
+            max_orphans,
+            max_age,
+            stats: OrphanStats::default(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn stats(&self) -> OrphanStats {
+        self.stats
+    }
+
+    pub fn contains(&self, hash: &[u8]) -> bool {
+        self.orphans.values().flatten().any(|o| o.hash == hash)
+    }
+
+    /// Whether this exact block is in the pool, not just a block with the same header
+    pub fn contains_block(&self, block: &Block) -> bool {
+        let id = sha256_digest(block);
+        self.orphans.values().flatten().any(|o| o.id == id)
+    }
+
+    /// Store a block whose parent is unknown, received at unix time `now`.
+    /// Expired orphans are dropped first, then the oldest ones if the pool is still full.
+    /// Returns false if the block was already in the pool or the pool doesn't keep any blocks.
+    pub fn insert(&mut self, block: Block, now: u64) -> bool {
+        if self.contains_block(&block) {
+            return false;
+        }
+        let hash = block.hash();
+        let id = sha256_digest(&block);
+        self.expire(now);
+        if self.max_orphans == 0 {
+            self.stats.evicted += 1;
+            return false;
+        }
+        while self.len >= self.max_orphans {
+            self.evict_oldest();
+        }
+
+        info!("Storing orphan block, {} orphans in the pool", self.len + 1);
+        self.orphans
+            .entry(block.header.previous_hash.clone())
+            .or_default()
+            .push(Orphan{ block, hash, id, received: now });
+        self.len += 1;
+        self.stats.added += 1;
+        true
+    }
+
+    /// Remove and return every orphan whose parent is the block with hash `parent`, oldest first
+    pub fn take_children(&mut self, parent: &[u8]) -> Vec<Block> {
+        let children = self.orphans.remove(parent).unwrap_or_default();
+        self.len -= children.len();
+        self.stats.connected += children.len() as u64;
+        if !children.is_empty() {
+            info!("Connecting {} orphan blocks", children.len());
+        }
+        children.into_iter().map(|o| o.block).collect()
+    }
+
+    /// Drop every orphan that was received more than `max_age` seconds before `now`
+    pub fn expire(&mut self, now: u64) {
+        let max_age = self.max_age;
+        let mut expired = 0;
+        self.orphans.retain(|_, children| {
+            let before = children.len();
+            children.retain(|o| now.saturating_sub(o.received) <= max_age);
+            expired += before - children.len();
+            !children.is_empty()
+        });
+        if expired > 0 {
+            warn!("Dropped {} expired orphan blocks", expired);
+            self.len -= expired;
+            self.stats.expired += expired as u64;
+        }
+    }
+
+    fn evict_oldest(&mut self) {
+        let oldest = self.orphans
+            .iter()
+            .flat_map(|(parent, children)| children.iter().enumerate().map(move |(ix, o)| (o.received, parent, ix)))
+            .min_by_key(|(received, _, _)| *received)
+            .map(|(_, parent, ix)| (parent.clone(), ix));
+        if let Some((parent, ix)) = oldest {
+            let children = self.orphans.get_mut(&parent).unwrap();
+            children.remove(ix);
+            if children.is_empty() {
+                self.orphans.remove(&parent);
+            }
+            warn!("Orphan pool is full, dropped the oldest orphan block");
+            self.len -= 1;
+            self.stats.evicted += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test{
+    // Imports
+    use super::*;
+    use crate::blockchain::BlockHeader;
+    use crate::merkletree::MerkleTree;
+
+    fn block(nonce: u64, previous_hash: Vec<u8>) -> Block {
+        let mut header = BlockHeader::new(previous_hash, 1, 0);
+        header.nonce = nonce;
+        Block::new(header, MerkleTree::new())
+    }
+
+    #[test]
+    fn limits() {
+        let mut pool = OrphanPool::new(2, 10);
+        assert!(pool.insert(block(0, vec![1]), 0));
+        assert!(!pool.insert(block(0, vec![1]), 0));
+        assert!(pool.insert(block(1, vec![2]), 5));
+
+        // The pool is full, the oldest orphan has to go
+        assert!(pool.insert(block(2, vec![2]), 6));
+        assert_eq!(pool.len(), 2);
+        assert!(!pool.contains(&block(0, vec![1]).hash()));
+        assert!(pool.take_children(&[1]).is_empty());
+
+        // A copy with the same header doesn't take the place of the original
+        let mut copy = block(2, vec![2]);
+        copy.seal = vec![1];
+        assert!(pool.insert(copy, 6));
+        assert!(pool.contains_block(&block(2, vec![2])));
+
+        // Both remaining orphans are too old by now
+        pool.expire(17);
+        assert!(pool.is_empty());
+        assert_eq!(pool.stats(), OrphanStats{ added: 4, connected: 0, expired: 2, evicted: 2 });
+
+        // A pool without room drops every block
+        let mut pool = OrphanPool::new(0, 10);
+        assert!(!pool.insert(block(0, vec![1]), 0));
+        assert!(pool.is_empty());
+    }
+
+    #[test]
+    fn take_children() {
+        let mut pool = OrphanPool::default();
+        pool.insert(block(0, vec![1]), 0);
+        pool.insert(block(1, vec![1]), 1);
+        pool.insert(block(2, vec![2]), 2);
+
+        let children = pool.take_children(&[1]);
+        assert_eq!(children.iter().map(|b| b.header.nonce).collect::<Vec<_>>(), vec![0, 1]);
+        assert_eq!(pool.len(), 1);
+        assert_eq!(pool.stats().connected, 2);
+    }
+}
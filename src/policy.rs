@@ -0,0 +1,104 @@
+use crate::mempool::Priority;
+use crate::transaction::SignedTransaction;
+use std::time::Duration;
+
+/// Number of bytes of transactions a miner puts into a single block by default
+pub const DEFAULT_MAX_BLOCK_SIZE: usize = 100_000;
+
+/// Decides how a miner assembles its blocks from the pending transactions.
+/// Every miner can use its own policy, which allows simulating miners that behave differently.
+pub trait MiningPolicy: Send {
+    /// The order in which pending transactions are put into a block
+    fn priority(&self) -> Priority {
+        Priority::FeeRate
+    }
+
+    /// Maximum number of bytes of transactions in a block
+    fn max_block_size(&self) -> usize {
+        DEFAULT_MAX_BLOCK_SIZE
+    }
+
+    /// Whether to start mining a block with `transactions`, after having waited
+    /// `waited` since the current tip of the chain was first seen.
+    /// Returning false makes the miner wait for more transactions.
+    fn ready(&self, transactions: &[SignedTransaction], waited: Duration) -> bool {
+        let _ = waited;
+        !transactions.is_empty()
+    }
+}
+
+/// A policy that is configured through its fields
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BasicPolicy{
+    /// Number of transactions a block needs before mining starts
+    pub min_transactions: usize,
+    pub max_block_size: usize,
+    /// Start mining after this time even with fewer than `min_transactions`, possibly an empty block
+    pub max_wait: Option<Duration>,
+    pub priority: Priority,
+}
+
+/// Mine as soon as there is a single transaction, best paying ones first
+impl Default for BasicPolicy{
+    fn default() -> Self {
+        BasicPolicy{
+            min_transactions: 1,
+            max_block_size: DEFAULT_MAX_BLOCK_SIZE,
+            max_wait: None,
+            priority: Priority::FeeRate,
+        }
+    }
+}
+
+impl BasicPolicy{
+    /// Collect the transactions with the highest tips, regardless of their size
+    pub fn greedy(min_transactions: usize) -> Self {
+        BasicPolicy{ min_transactions, priority: Priority::Tip, ..BasicPolicy::default() }
+    }
+
+    /// Include transactions in the order they arrived
+    pub fn fifo(min_transactions: usize) -> Self {
+        BasicPolicy{ min_transactions, priority: Priority::Arrival, ..BasicPolicy::default() }
+    }
+}
+
+impl MiningPolicy for BasicPolicy{
+    fn priority(&self) -> Priority {
+        self.priority
+    }
+
+    fn max_block_size(&self) -> usize {
+        self.max_block_size
+    }
+
+    fn ready(&self, transactions: &[SignedTransaction], waited: Duration) -> bool {
+        transactions.len() >= self.min_transactions || self.max_wait.is_some_and(|max| waited >= max)
+    }
+}
+
+#[cfg(test)]
+mod test{
+    // Imports
+    use super::*;
+    use crate::amount::Amount;
+    use crate::trader::Trader;
+    use crate::transaction::Transaction;
+
+    #[test]
+    fn basic_policy() {
+        let alice = Trader::new();
+        let t = Transaction::new(alice.public_key.clone(), alice.public_key.clone(), Amount::ZERO);
+        let transactions = vec![alice.sign(t); 2];
+        let second = Duration::from_secs(1);
+
+        let policy = BasicPolicy::default();
+        assert!(!policy.ready(&[], second));
+        assert!(policy.ready(&transactions[..1], Duration::ZERO));
+
+        // Wait for three transactions, but no longer than a second
+        let policy = BasicPolicy{ max_wait: Some(second), ..BasicPolicy::fifo(3) };
+        assert!(!policy.ready(&transactions, Duration::ZERO));
+        assert!(policy.ready(&transactions, second));
+        assert!(policy.ready(&[], second));
+    }
+}
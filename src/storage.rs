@@ -0,0 +1,456 @@
+use crate::blockchain::Block;
+use crate::encoding::{Encode, Decode, Reader, DecodeError};
+use crate::utils::sha256;
+use log::{info, warn};
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+/// File that holds the block records, in the order they were appended
+const BLOCKS_FILE: &str = "blocks.dat";
+/// File that holds the location of every block record
+const INDEX_FILE: &str = "index.dat";
+/// Number of hash bytes at the end of every block record
+const CHECKSUM_SIZE: usize = 4;
+
+#[derive(Debug)]
+pub enum StorageError{
+    Io(io::Error),
+    /// A stored block can't be read back although its record is complete
+    Decode(DecodeError),
+    /// The stored chain starts at a different genesis block than the config describes
+    GenesisMismatch,
+}
+
+impl fmt::Display for StorageError{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StorageError::Io(e) => write!(f, "block store I/O failed: {}", e),
+            StorageError::Decode(e) => write!(f, "stored block is malformed: {}", e),
+            StorageError::GenesisMismatch => write!(f, "stored chain belongs to a different genesis block"),
+        }
+    }
+}
+
+impl std::error::Error for StorageError {}
+
+impl From<io::Error> for StorageError{
+    fn from(e: io::Error) -> Self {
+        StorageError::Io(e)
+    }
+}
+
+impl From<DecodeError> for StorageError{
+    fn from(e: DecodeError) -> Self {
+        StorageError::Decode(e)
+    }
+}
+
+/// Location of a block record in the blocks file
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct IndexEntry{
+    hash: Vec<u8>,
+    offset: u64,
+    /// Size of the whole record, including the length prefix and the checksum
+    size: u64,
+}
+
+impl Encode for IndexEntry{
+    fn encode(&self, buf: &mut Vec<u8>) {
+        self.hash.encode(buf);
+        self.offset.encode(buf);
+        self.size.encode(buf);
+    }
+}
+
+impl Decode for IndexEntry{
+    fn decode(reader: &mut Reader) -> Result<Self, DecodeError> {
+        Ok(IndexEntry{
+            hash: Vec::decode(reader)?,
+            offset: u64::decode(reader)?,
+            size: u64::decode(reader)?,
+        })
+    }
+}
+
+/// Append-only storage of blocks in a directory.
+///
+/// Every block is appended to the blocks file as a record of its encoded length, the encoded
+/// block and the first bytes of its hash as checksum. The index file lists where each record
+/// starts. Both writes are flushed to disk before `append` returns, so a crash can at most
+/// leave an incomplete record at the end of either file. When the index doesn't match the
+/// blocks file on open, the index is rebuilt from the blocks, see `reindex`.
+#[derive(Debug)]
+pub struct BlockStore{
+    dir: PathBuf,
+    blocks: File,
+    index: File,
+    /// Location of every stored block, in the order they were appended
+    entries: Vec<IndexEntry>,
+    /// Position in `entries`, keyed by block hash
+    positions: HashMap<Vec<u8>, usize>,
+}
+
+impl BlockStore{
+    /// Open the store in `dir`, which is created if it doesn't exist
+    pub fn open<P: AsRef<Path>>(dir: P) -> Result<Self, StorageError> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)?;
+        let blocks = open_append(&dir.join(BLOCKS_FILE))?;
+        let index = open_append(&dir.join(INDEX_FILE))?;
+        let mut store = BlockStore{ dir, blocks, index, entries: Vec::new(), positions: HashMap::new() };
+        if !store.load_index()? {
+            warn!("Block index doesn't match {}, reindexing", BLOCKS_FILE);
+            store.reindex()?;
+        }
+        Ok(store)
+    }
+
+    /// Read the index file. Returns false if it is malformed or doesn't cover the blocks file exactly.
+    fn load_index(&mut self) -> Result<bool, StorageError> {
+        let mut data = Vec::new();
+        (&self.index).seek(SeekFrom::Start(0))?;
+        (&self.index).read_to_end(&mut data)?;
+
+        let mut reader = Reader::new(&data);
+        let mut entries = Vec::new();
+        let mut end = 0;
+        while !reader.is_empty() {
+            let entry = match IndexEntry::decode(&mut reader) {
+                Ok(entry) => entry,
+                Err(_) => return Ok(false),
+            };
+            // Unreadable records are skipped by the index, so it may have gaps
+            if entry.offset < end {
+                return Ok(false);
+            }
+            end = entry.offset + entry.size;
+            entries.push(entry);
+        }
+        if end != self.blocks.metadata()?.len() {
+            return Ok(false);
+        }
+        self.set_entries(entries);
+        Ok(true)
+    }
+
+    /// Rebuild the index from the blocks file.
+    /// Records that fail their checksum or can't be decoded are skipped, since their length
+    /// prefix still tells where the next record starts. An incomplete record can only be the
+    /// result of a crash during `append`, it is cut off together with unreadable records behind the last valid one.
+    pub fn reindex(&mut self) -> Result<(), StorageError> {
+        let mut data = Vec::new();
+        (&self.blocks).seek(SeekFrom::Start(0))?;
+        (&self.blocks).read_to_end(&mut data)?;
+
+        let mut entries: Vec<IndexEntry> = Vec::new();
+        let mut offset = 0;
+        while offset < data.len() {
+            let (bytes, size) = match split_record(&data[offset..]) {
+                Ok(record) => record,
+                Err(_) => break,
+            };
+            match decode_record(bytes) {
+                Ok(block) => entries.push(IndexEntry{ hash: block.hash(), offset: offset as u64, size: size as u64 }),
+                Err(e) => warn!("Skipping the unreadable record at offset {} of {}: {}", offset, BLOCKS_FILE, e),
+            }
+            offset += size;
+        }
+        let end = entries.last().map_or(0, |e| e.offset + e.size);
+        if end < data.len() as u64 {
+            warn!("Dropping {} bytes from the end of {}", data.len() as u64 - end, BLOCKS_FILE);
+            self.blocks.set_len(end)?;
+            self.blocks.sync_all()?;
+        }
+
+        // Replace the index at once, so a crash leaves either the old or the new one behind
+        let mut buf = Vec::new();
+        for entry in entries.iter() {
+            entry.encode(&mut buf);
+        }
+        let path = self.dir.join(INDEX_FILE);
+        let tmp = self.dir.join(format!("{}.tmp", INDEX_FILE));
+        let mut file = File::create(&tmp)?;
+        file.write_all(&buf)?;
+        file.sync_all()?;
+        fs::rename(&tmp, &path)?;
+        sync_dir(&self.dir)?;
+        self.index = open_append(&path)?;
+
+        info!("Reindexed {} stored blocks", entries.len());
+        self.set_entries(entries);
+        Ok(())
+    }
+
+    fn set_entries(&mut self, entries: Vec<IndexEntry>) {
+        self.positions = entries.iter().enumerate().map(|(ix, e)| (e.hash.clone(), ix)).collect();
+        self.entries = entries;
+    }
+
+    /// Append `b` to the store, unless it is stored already.
+    /// Returns once the block and its index entry are on disk.
+    pub fn append(&mut self, b: &Block) -> io::Result<()> {
+        let hash = b.hash();
+        if self.contains(&hash) {
+            return Ok(());
+        }
+        let bytes = b.to_bytes();
+        let mut record = Vec::with_capacity(bytes.len() + 12);
+        (bytes.len() as u64).encode(&mut record);
+        record.extend_from_slice(&bytes);
+        record.extend_from_slice(&sha256(&bytes)[..CHECKSUM_SIZE]);
+
+        let offset = self.blocks.metadata()?.len();
+        if let Err(e) = self.blocks.write_all(&record).and_then(|_| self.blocks.sync_data()) {
+            // Don't leave a partial record in front of the next one
+            let _ = self.blocks.set_len(offset);
+            return Err(e);
+        }
+
+        // If this write fails, the index falls behind the blocks file and is rebuilt on the next open
+        let entry = IndexEntry{ hash, offset, size: record.len() as u64 };
+        self.index.write_all(&entry.to_bytes())?;
+        self.index.sync_data()?;
+
+        self.positions.insert(entry.hash.clone(), self.entries.len());
+        self.entries.push(entry);
+        Ok(())
+    }
+
+    /// The directory that holds the store
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    pub fn contains(&self, hash: &[u8]) -> bool {
+        self.positions.contains_key(hash)
+    }
+
+    /// Number of stored blocks
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Read the block with hash `hash` from disk
+    pub fn get(&self, hash: &[u8]) -> Result<Option<Block>, StorageError> {
+        let entry = match self.positions.get(hash) {
+            Some(&ix) => &self.entries[ix],
+            None => return Ok(None),
+        };
+        let mut data = vec![0; entry.size as usize];
+        (&self.blocks).seek(SeekFrom::Start(entry.offset))?;
+        (&self.blocks).read_exact(&mut data)?;
+        Ok(Some(read_record(&data)?.0))
+    }
+
+    /// Read every stored block, in the order they were appended
+    pub fn blocks(&self) -> Result<Vec<Block>, StorageError> {
+        let mut data = Vec::new();
+        (&self.blocks).seek(SeekFrom::Start(0))?;
+        (&self.blocks).read_to_end(&mut data)?;
+        let mut blocks = Vec::with_capacity(self.entries.len());
+        for entry in self.entries.iter() {
+            let start = entry.offset as usize;
+            let end = start + entry.size as usize;
+            blocks.push(read_record(data.get(start..end).ok_or(DecodeError::UnexpectedEnd)?)?.0);
+        }
+        Ok(blocks)
+    }
+}
+
+fn open_append(path: &Path) -> io::Result<File> {
+    OpenOptions::new().read(true).append(true).create(true).open(path)
+}
+
+/// Flush a rename within `dir` to disk, which needs a sync of the directory itself
+#[cfg(unix)]
+fn sync_dir(dir: &Path) -> io::Result<()> {
+    File::open(dir)?.sync_all()
+}
+
+#[cfg(not(unix))]
+fn sync_dir(_dir: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+/// Decode the record at the start of `data`, returning the block and the size of the record
+fn read_record(data: &[u8]) -> Result<(Block, usize), DecodeError> {
+    let (bytes, size) = split_record(data)?;
+    Ok((decode_record(bytes)?, size))
+}
+
+/// Split off the record at the start of `data`, returning its checksummed bytes and its size.
+/// Only fails if the record is incomplete.
+fn split_record(data: &[u8]) -> Result<(&[u8], usize), DecodeError> {
+    let mut reader = Reader::new(data);
+    let len = usize::try_from(u64::decode(&mut reader)?).map_err(|_| DecodeError::UnexpectedEnd)?;
+    let bytes = reader.take(len.checked_add(CHECKSUM_SIZE).ok_or(DecodeError::UnexpectedEnd)?)?;
+    Ok((bytes, data.len() - reader.remaining()))
+}
+
+/// Check the checksum at the end of `bytes` and decode the block in front of it
+fn decode_record(bytes: &[u8]) -> Result<Block, DecodeError> {
+    let (block, checksum) = bytes.split_at(bytes.len() - CHECKSUM_SIZE);
+    if sha256(block)[..CHECKSUM_SIZE] != *checksum {
+        return Err(DecodeError::InvalidValue("block record checksum"));
+    }
+    Block::from_bytes(block)
+}
+
+#[cfg(test)]
+mod test{
+    // Imports
+    use super::*;
+    use crate::blockchain::Blockchain;
+    use crate::error::Error;
+    use crate::genesis::GenesisConfig;
+    use crate::test_utils::mine;
+    use crate::trader::Trader;
+    use crate::transaction::Transaction;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("blockstore-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[test]
+    fn reopen_chain() {
+        let alice = Trader::new();
+        let bob = Trader::new();
+        let mut config = GenesisConfig::default();
+        config.allocations.push((alice.public_key.clone(), "100".parse().unwrap()));
+        let dir = temp_dir("reopen");
+
+        let mut bc = Blockchain::open(&config, &dir).unwrap();
+        let b = mine(&bc, &bob, Vec::new());
+        bc.add(b).unwrap();
+        let payment = alice.sign(Transaction::new(alice.public_key.clone(), bob.public_key.clone(), "10".parse().unwrap()));
+        let b = mine(&bc, &bob, vec![payment]);
+        bc.add(b).unwrap();
+
+        let reopened = Blockchain::open(&config, &dir).unwrap();
+        assert_eq!(reopened.blocks.len(), 3);
+        assert_eq!(reopened.tip_hash(), bc.tip_hash());
+        assert_eq!(reopened.total_work(), bc.total_work());
+        for key in [&alice.public_key, &bob.public_key].iter() {
+            assert_eq!(reopened.ledger().balance(key), bc.ledger().balance(key));
+        }
+        drop(bc);
+
+        // A store belongs to one network only
+        config.chain_id = "othernet".to_string();
+        assert!(matches!(Blockchain::open(&config, &dir), Err(Error::Storage(StorageError::GenesisMismatch))));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn recover_from_crash() {
+        let miner = Trader::new();
+        let config = GenesisConfig::default();
+        let dir = temp_dir("crash");
+
+        let mut bc = Blockchain::open(&config, &dir).unwrap();
+        for _ in 0..3 {
+            let b = mine(&bc, &miner, Vec::new());
+            bc.add(b).unwrap();
+        }
+        let tip = bc.tip_hash();
+        drop(bc);
+
+        // A crash in the middle of an append leaves a partial record behind
+        let mut blocks = OpenOptions::new().append(true).open(dir.join(BLOCKS_FILE)).unwrap();
+        blocks.write_all(&[0, 0, 0, 0, 0, 0, 1, 0, 42]).unwrap();
+        drop(blocks);
+        let store = BlockStore::open(&dir).unwrap();
+        assert_eq!(store.len(), 4);
+        assert!(store.get(&tip).unwrap().is_some());
+        drop(store);
+
+        // Without an index, it is rebuilt from the blocks
+        fs::remove_file(dir.join(INDEX_FILE)).unwrap();
+        let mut bc = Blockchain::open(&config, &dir).unwrap();
+        assert_eq!(bc.tip_hash(), tip);
+
+        // The recovered store keeps accepting blocks
+        let b = mine(&bc, &miner, Vec::new());
+        bc.add(b).unwrap();
+        let tip = bc.tip_hash();
+        drop(bc);
+        let bc = Blockchain::open(&config, &dir).unwrap();
+        assert_eq!(bc.tip_hash(), tip);
+        assert_eq!(bc.blocks.len(), 5);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn skip_corrupt_records() {
+        let miner = Trader::new();
+        let dir = temp_dir("corrupt");
+        let mut bc = Blockchain::open(&GenesisConfig::default(), &dir).unwrap();
+        for _ in 0..3 {
+            let b = mine(&bc, &miner, Vec::new());
+            bc.add(b).unwrap();
+        }
+        let blocks = bc.blocks.clone();
+        drop(bc);
+
+        // Flip a byte within the second block and drop the index
+        let store = BlockStore::open(&dir).unwrap();
+        let offset = store.entries[1].offset + 20;
+        drop(store);
+        let mut data = fs::read(dir.join(BLOCKS_FILE)).unwrap();
+        data[offset as usize] ^= 0xff;
+        fs::write(dir.join(BLOCKS_FILE), &data).unwrap();
+        fs::remove_file(dir.join(INDEX_FILE)).unwrap();
+
+        // Only the corrupt record is lost, the blocks behind it are still indexed
+        let store = BlockStore::open(&dir).unwrap();
+        assert_eq!(store.len(), 3);
+        assert!(!store.contains(&blocks[1].hash()));
+        assert!(store.get(&blocks[3].hash()).unwrap().is_some());
+        drop(store);
+
+        // The index has a gap where the corrupt record is, which is fine when reopening
+        let store = BlockStore::open(&dir).unwrap();
+        assert!(store.entries[1].offset > store.entries[0].offset + store.entries[0].size);
+        assert_eq!(store.blocks().unwrap().len(), 3);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn skip_rejected_blocks() {
+        let alice = Trader::new();
+        let bob = Trader::new();
+        let mut config = GenesisConfig::default();
+        config.allocations.push((alice.public_key.clone(), "100".parse().unwrap()));
+        let dir = temp_dir("rejected");
+
+        // The ledger rejects the overspending block after every other check passed
+        let mut bc = Blockchain::open(&config, &dir).unwrap();
+        let overspend = alice.sign(Transaction::new(alice.public_key.clone(), bob.public_key.clone(), "1000".parse().unwrap()));
+        let rejected = mine(&bc, &bob, vec![overspend]);
+        assert!(bc.add(rejected.clone()).is_err());
+        let b = mine(&bc, &bob, Vec::new());
+        bc.add(b).unwrap();
+        let tip = bc.tip_hash();
+        drop(bc);
+
+        let store = BlockStore::open(&dir).unwrap();
+        assert_eq!(store.len(), 2);
+        assert!(!store.contains(&rejected.hash()));
+        drop(store);
+
+        let bc = Blockchain::open(&config, &dir).unwrap();
+        assert_eq!(bc.tip_hash(), tip);
+        assert_eq!(bc.blocks.len(), 2);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
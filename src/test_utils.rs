@@ -0,0 +1,54 @@
+//! Fixtures shared by the tests of all modules
+use crate::amount::Amount;
+use crate::blockchain::{Block, BlockHeader, Blockchain};
+use crate::merkletree::MerkleTree;
+use crate::miner::collected_fees;
+use crate::trader::Trader;
+use crate::transaction::{Transaction, SignedTransaction};
+
+pub fn coins(n: u64) -> Amount {
+    Amount::from_coins(n).unwrap()
+}
+
+/// Build an unsealed block with a coinbase and `transactions` on top of `parent`, which doesn't
+/// need to be the tip. The coinbase pays the block reward and all fees and tips to `miner`.
+/// On top of the tip, the consensus fields are set by the chain, otherwise the difficulty of the parent is kept.
+pub fn block_on(parent: &Block, bc: &Blockchain, miner: &Trader, transactions: Vec<SignedTransaction>, timestamp: u64) -> Block {
+    let height = parent.header.height + 1;
+    let amount = bc.block_reward().checked_add(collected_fees(&transactions)).unwrap();
+    let mut tree = MerkleTree::new();
+    tree.add(miner.sign(Transaction::coinbase(miner.public_key.clone(), amount, height)));
+    for st in transactions {
+        tree.add(st);
+    }
+    let mut header = BlockHeader::new(parent.hash(), height, timestamp);
+    header.difficulty = parent.header.difficulty;
+    if parent.hash() == bc.tip_hash() {
+        bc.prepare(&mut header);
+    }
+    Block::new(header, tree)
+}
+
+/// Roll the nonce until the block meets its difficulty
+pub fn solve(b: &mut Block) {
+    while !b.header.meets_difficulty() {
+        b.header.nonce += 1;
+    }
+}
+
+/// Mine a block on top of `parent`, which doesn't need to be the tip
+pub fn mine_on(parent: &Block, bc: &Blockchain, miner: &Trader, transactions: Vec<SignedTransaction>, timestamp: u64) -> Block {
+    let mut b = block_on(parent, bc, miner, transactions, timestamp);
+    solve(&mut b);
+    b
+}
+
+/// Mine a block with `transactions` on top of the chain, at the earliest valid timestamp
+pub fn mine(bc: &Blockchain, miner: &Trader, transactions: Vec<SignedTransaction>) -> Block {
+    mine_on(&bc.blocks[bc.blocks.len() - 1], bc, miner, transactions, bc.min_timestamp())
+}
+
+/// Mine a block with only a coinbase on top of the chain
+pub fn mine_at(bc: &Blockchain, miner: &Trader, timestamp: u64) -> Block {
+    mine_on(&bc.blocks[bc.blocks.len() - 1], bc, miner, Vec::new(), timestamp)
+}
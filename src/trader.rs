@@ -1,11 +1,20 @@
 use log::{info, warn};
 use rsa::{RSAPrivateKey, RSAPublicKey, PaddingScheme, Hash};
 use rand::rngs::OsRng;
-use crate::blockchain::{Block, Blockchain};
+use crate::amount::Amount;
+use crate::blockchain::{Block, BlockHeader, Blockchain, BlockStatus, BlockError};
+use crate::error::Error;
+use crate::genesis::GenesisConfig;
+use crate::mempool::Mempool;
+use crate::consensus::{ConsensusEngine, SealContext};
+use crate::miner::{MinerStats, Interrupt, collected_fees};
+use crate::policy::{MiningPolicy, BasicPolicy};
 use crate::merkletree::MerkleTree;
 use crate::transaction::{Transaction, SignedTransaction};
-use crate::utils::{random_id, sha256_digest, get_unix_timestamp};
+use crate::utils::{random_id, get_unix_timestamp};
 use std::{
+    fmt,
+    time::{Duration, Instant},
     thread,
     thread::JoinHandle,
     sync::{
@@ -20,6 +29,31 @@ type STSender = Sender<SignedTransaction>;
 type STReceiver = Receiver<SignedTransaction>;
 type Shared<T> = Arc<Mutex<T>>;
 
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum NetworkError{
+    /// Some peers were unreachable because their end of the channel was dropped
+    Disconnected{ peers: usize },
+    /// A thread panicked while holding the lock of shared state
+    Poisoned,
+}
+
+impl fmt::Display for NetworkError{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            NetworkError::Disconnected{ peers } => write!(f, "{} peers are disconnected", peers),
+            NetworkError::Poisoned => write!(f, "shared state is poisoned"),
+        }
+    }
+}
+
+impl std::error::Error for NetworkError {}
+
+impl<T> From<std::sync::PoisonError<T>> for NetworkError{
+    fn from(_: std::sync::PoisonError<T>) -> Self {
+        NetworkError::Poisoned
+    }
+}
+
 pub struct Trader {
     id: String,
     pub public_key: RSAPublicKey,
@@ -27,35 +61,65 @@ pub struct Trader {
     pub known_miners: Shared<Vec<STSender>>,
     known_traders: Shared<Vec<Sender<Block>>>,
     blockchain: Shared<Blockchain>,
+    /// Transactions received by our miners that are not part of a block yet
+    mempool: Shared<Mempool>,
+    miner_stats: Shared<MinerStats>,
     block_sender: Sender<Block>,
 }
 
+impl Default for Trader{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Trader{
     pub fn new() -> Trader {
-        // Generate a random 256bit RSA key pair
+        Trader::with_key(Trader::generate_key(), &GenesisConfig::default())
+            .expect("Genesis block without allocations is always valid")
+    }
+
+    /// Generate a random 512bit RSA private key
+    pub fn generate_key() -> RSAPrivateKey {
         let mut rng = OsRng;
-        let private_key = RSAPrivateKey::new(&mut rng, 512).expect("Failed to generate a key");
+        RSAPrivateKey::new(&mut rng, 512).expect("Failed to generate a key")
+    }
+
+    /// Create a trader with a known key, whose blockchain starts with the genesis block of `genesis`.
+    /// Fails if the ledger rejects the genesis block.
+    pub fn with_key(private_key: RSAPrivateKey, genesis: &GenesisConfig) -> Result<Trader, Error> {
+        Trader::with_consensus(private_key, genesis, genesis.consensus())
+    }
+
+    /// Create a trader whose blockchain follows the rules of `consensus`, e.g. a proof-of-work
+    /// engine that mines with a given number of threads
+    pub fn with_consensus(private_key: RSAPrivateKey, genesis: &GenesisConfig, consensus: Arc<dyn ConsensusEngine>) -> Result<Trader, Error> {
         let public_key = RSAPublicKey::from(&private_key);
 
         let (block_sender, block_receiver) = mpsc::channel();
-        let blockchain = Arc::new(Mutex::new(Blockchain::new()));
+        let blockchain = Blockchain::with_consensus(genesis, consensus)?;
+        let blockchain = Arc::new(Mutex::new(blockchain));
+        let mempool = Arc::new(Mutex::new(Mempool::default()));
         let id = random_id(5);
 
-        Trader::spawn_trader_thread(&id, blockchain.clone(), block_receiver);
+        Trader::spawn_trader_thread(&id, blockchain.clone(), mempool.clone(), block_receiver);
 
-        Trader {
-            id: id, 
+        Ok(Trader {
+            id,
             public_key: public_key.clone(),
-            private_key: private_key,
+            private_key,
             blockchain: blockchain.clone(),
+            mempool,
+            miner_stats: Arc::new(Mutex::new(MinerStats::default())),
             known_miners: Arc::new(Mutex::new(Vec::new())),
             known_traders: Arc::new(Mutex::new(Vec::new())),
-            block_sender: block_sender,
-        }
+            block_sender,
+        })
     }
     
-    /// Spawn a new thread that listens for incoming transactions and keeps track of the local blockchain
-    pub fn spawn_trader_thread(id: &str, blockchain: Arc<Mutex<Blockchain>>, block_receiver: Receiver<Block>) -> JoinHandle<()> {
+    /// Spawn a new thread that listens for incoming blocks and keeps track of the local blockchain.
+    /// Transactions that are confirmed by a new block are removed from the mempool.
+    pub fn spawn_trader_thread(id: &str, blockchain: Shared<Blockchain>, mempool: Shared<Mempool>, block_receiver: Receiver<Block>) -> JoinHandle<()> {
         info!("Spawning new Trader thread {}", id);
         let name = format!("[Trader]{}", id);
         thread::Builder::new().name(name).spawn(move|| {
@@ -63,88 +127,165 @@ impl Trader{
                 // Check for new transactions to be added to the blockchain
                 match block_receiver.try_recv() {
                     Ok(block) => {
-                        if block.is_valid() {
-                            info!("Received new Block, now adding it to the Blockchain");
-                            // Acquire thread lock
-                            if let Ok(mut bc) = blockchain.lock() {
-                                bc.add(block);
+                        info!("Received new Block, now adding it to the Blockchain");
+                        // Acquire thread lock
+                        if let Ok(mut bc) = blockchain.lock() {
+                            match add_block(&mut bc, &mempool, block) {
+                                Ok(BlockStatus::Reorganized{ depth }) => {
+                                    info!("Switched to a heavier branch, {} blocks were rolled back", depth);
+                                },
+                                Ok(BlockStatus::SideBranch) => info!("Stored block on a side branch"),
+                                Ok(BlockStatus::Orphaned) => info!("Stored block with unknown parent in the orphan pool"),
+                                Ok(BlockStatus::Dropped) => warn!("Dropped block with unknown parent, the orphan pool has no room"),
+                                Ok(_) => {},
+                                Err(e) => warn!("Rejected block: {}", e),
                             }
                         }
-                        else {
-                            warn!("Received an invalid block");
-                        }
                     },
-                    Err(error) => {
-                        if let mpsc::TryRecvError::Disconnected = error {
-                            panic!("Traders transaction channel disconnected");
-                        }
+                    Err(mpsc::TryRecvError::Disconnected) => {
+                        info!("Block channel disconnected, stopping Trader thread");
+                        break;
                     },
+                    Err(mpsc::TryRecvError::Empty) => {},
                 };
 
             }
         }).unwrap()
     }
 
+    /// Spawn a miner that mines as soon as there is a single transaction, best paying ones first
     pub fn spawn_miner_thread(&self) -> Sender<SignedTransaction>{
+        self.spawn_miner_thread_with_policy(BasicPolicy::default())
+    }
+
+    /// Spawn a miner that assembles its blocks according to `policy` and seals them with the consensus engine of the chain.
+    /// The miner starts over as soon as the chain gets a new tip or better paying transactions arrive.
+    pub fn spawn_miner_thread_with_policy<P: MiningPolicy + 'static>(&self, policy: P) -> Sender<SignedTransaction>{
         // Clone Mutexes
         let blockchain = self.blockchain.clone();
+        let mempool = self.mempool.clone();
+        let stats = self.miner_stats.clone();
         let known_traders = self.known_traders.clone();
+        let public_key = self.public_key.clone();
+        let private_key = self.private_key.clone();
         let (transaction_sender, transaction_receiver): (STSender, STReceiver) = mpsc::channel();
 
-        // The mining policy can vary from miner to miner, this is a rather simple one:
-        // the miner waits for a fixed number of transactions to arrive before he 
-        // starts mining a new block, regardless of tips etc.
+        // Incoming transactions are collected in the mempool, the policy decides
+        // which of them go into a block and when to start mining it.
         info!("Spawning new Miner thread {}", self.id);
         let name = format!("[Miner]{}", self.id);
         thread::Builder::new().name(name).spawn(move|| {
+            let mut waiting = false;
+            let mut tip = Vec::new();
+            let mut tip_seen = Instant::now();
             loop {
-                let mut previous_hash = Vec::new();
-                if let Ok(bc) = blockchain.lock() {
-                    previous_hash = sha256_digest(&bc.blocks[bc.blocks.len() - 1]);
+                // Only block on the channel if the policy wasn't ready the last time
+                let mut received = Vec::new();
+                if waiting {
+                    match transaction_receiver.recv_timeout(Duration::from_millis(100)) {
+                        Ok(t) => received.push(t),
+                        Err(mpsc::RecvTimeoutError::Timeout) => {},
+                        Err(mpsc::RecvTimeoutError::Disconnected) => {
+                            info!("Transaction channel disconnected, stopping Miner thread");
+                            return;
+                        },
+                    }
                 }
 
-                let mut b = Block {
-                    id: random_id(10),
-                    transactions: MerkleTree::new(),
-                    nonce: 0,
-                    timestamp: get_unix_timestamp(),
-                    previous_hash: previous_hash, 
+                let mut previous_hash = Vec::new();
+                let mut reward = Amount::ZERO;
+                let mut prepared = None;
+                let mut transactions = Vec::new();
+                if let (Ok(bc), Ok(mut pool)) = (blockchain.lock(), mempool.lock()) {
+                    receive_transactions(received, &transaction_receiver, &bc, &mut pool);
+                    previous_hash = bc.tip_hash();
+                    if previous_hash != tip {
+                        tip = previous_hash.clone();
+                        tip_seen = Instant::now();
+                    }
+                    reward = bc.block_reward();
+                    // With proof-of-authority or proof-of-stake, wait until it's our turn
+                    if !bc.can_seal(&public_key) {
+                        waiting = true;
+                        continue;
+                    }
+                    transactions = pool.template(bc.ledger(), policy.max_block_size(), policy.priority());
+                    let timestamp = get_unix_timestamp().max(bc.min_timestamp());
+                    let mut header = BlockHeader::new(previous_hash.clone(), bc.next_height(), timestamp);
+                    bc.prepare(&mut header);
+                    prepared = Some((header, bc.consensus()));
+                }
+                waiting = !policy.ready(&transactions, tip_seen.elapsed());
+                let (header, consensus) = match prepared {
+                    Some(prepared) if !waiting => prepared,
+                    _ => continue,
                 };
 
-                // Wait for a single transaction
-                info!("Waiting for new transactions");
-                while b.transactions.len() < 1 {
-                    let t = transaction_receiver.recv().unwrap();
+                // Pay the block reward plus all fees and tips to ourself
+                let fees = collected_fees(&transactions);
+                let amount = reward.checked_add(fees).expect("Fees of valid transactions cannot overflow");
+                let coinbase = Transaction::coinbase(public_key.clone(), amount, header.height);
 
-                    // Validate the Transaction before adding it to the block
-                    if t.is_valid(){
-                        info!("Received a new, valid transaction");
-                        b.transactions.add(t);
+                let mut tree = MerkleTree::new();
+                tree.add(sign_transaction(&private_key, coinbase));
+                for st in transactions {
+                    tree.add(st);
+                }
+                let mut b = Block::new(header, tree);
+
+                // Seal the block according to the consensus engine, e.g. find its proof-of-work.
+                // Give up once the block would be stale or there are better paying transactions.
+                info!("Starting to seal the block");
+                let started = Instant::now();
+                let mut check = || {
+                    let (bc, mut pool) = match (blockchain.lock(), mempool.lock()) {
+                        (Ok(bc), Ok(pool)) => (bc, pool),
+                        _ => return None,
+                    };
+                    if bc.tip_hash() != previous_hash {
+                        return Some(Interrupt::NewTip);
                     }
-                    else{
-                        warn!("Received an invalid transaction");
+                    if receive_transactions(Vec::new(), &transaction_receiver, &bc, &mut pool) {
+                        let better = pool.template(bc.ledger(), policy.max_block_size(), policy.priority());
+                        if collected_fees(&better) > fees {
+                            return Some(Interrupt::BetterTransactions);
+                        }
                     }
+                    None
+                };
+                let mut ctx = SealContext::new(&private_key, &mut check);
+                let result = consensus.seal(&mut b, &mut ctx);
+                let hashes = ctx.attempts;
+                if let Ok(mut stats) = stats.lock() {
+                    stats.hashes += hashes;
+                    stats.mining_time += started.elapsed();
+                    if let Err(reason) = result {
+                        stats.interrupted(reason);
+                    }
+                }
+                if let Err(reason) = result {
+                    info!("Stopped mining: {:?}", reason);
+                    continue;
                 }
+                info!("Solved: {:?} after {} hashes ({:.0} H/s)", b.header.nonce, hashes, hashes as f64 / started.elapsed().as_secs_f64());
 
-                // Find Proof-of-Work
-                let mut nonce_found = false;
-                let mut nonce = 0;
-                info!("Starting to search for the correct nonce");
-                while !nonce_found {
-                    b.nonce = nonce;
-                    
-                    let digest = sha256_digest(&b);
-                    if digest[0] == 0{
-                        info!("Solved: {:?}", nonce);
-                        nonce_found = true;
+                // Add the block to our own chain, then send it to all other traders.
+                // If another block was found in the meantime, the block is stale but might still win.
+                if let Ok(mut bc) = blockchain.lock() {
+                    let status = add_block(&mut bc, &mempool, b.clone());
+                    if let Ok(mut stats) = stats.lock() {
+                        match status {
+                            Ok(BlockStatus::Extended) => stats.blocks += 1,
+                            _ => {
+                                warn!("Mined a stale block: {:?}", status);
+                                stats.stale_blocks += 1;
+                            },
+                        }
                     }
-                    nonce += 1;
                 }
-
-                // Send the solved block to all other traders
-                if let Ok(mut traders) = known_traders.lock() {
-                    for peer in traders.iter_mut() {
-                        peer.send(b.clone()).unwrap();
+                if let Ok(traders) = known_traders.lock() {
+                    if let Err(e) = send_all(&traders, &b) {
+                        warn!("Failed to broadcast block: {}", e);
                     }
                 }
             }
@@ -152,41 +293,138 @@ impl Trader{
         transaction_sender
     }
 
+    /// What the miners of this trader have done so far
+    pub fn miner_stats(&self) -> MinerStats {
+        match self.miner_stats.lock() {
+            Ok(stats) => *stats,
+            Err(_) => MinerStats::default(),
+        }
+    }
+
+    /// The nonce of the next transaction according to the local blockchain.
+    /// Transactions that were broadcast but are not yet part of a block are not counted.
+    pub fn next_nonce(&self) -> u64 {
+        match self.blockchain.lock() {
+            Ok(bc) => bc.ledger().next_nonce(&self.public_key),
+            Err(_) => 0,
+        }
+    }
+
     /// Sign a given Transaction with the RSA private key
     pub fn sign(&self, t: Transaction) -> SignedTransaction {
-        let hashed = sha256_digest(&t);
-        let padding = PaddingScheme::new_pkcs1v15_sign(Some(Hash::SHA2_256));
-        let s = self.private_key.sign(padding, &hashed).unwrap();
-        
-        SignedTransaction{
-            transaction: t,
-            signature: s
-        }
+        sign_transaction(&self.private_key, t)
     }
 
     /// Link to two traders together, creating a p2p network
-    pub fn link(&self, partner: &mut Trader){
-        if let Ok(mut traders) = self.known_traders.lock() {
-            traders.push(partner.block_sender.clone());
-        }
-        if let Ok(mut traders) = partner.known_traders.lock() {
-            traders.push(self.block_sender.clone());
-        }
+    pub fn link(&self, partner: &mut Trader) -> Result<(), Error> {
+        self.known_traders.lock().map_err(NetworkError::from)?.push(partner.block_sender.clone());
+        partner.known_traders.lock().map_err(NetworkError::from)?.push(self.block_sender.clone());
+        Ok(())
     }
 
-    pub fn register_miner(&self, miner: &Sender<SignedTransaction>) {
-        if let Ok(mut miners) = self.known_miners.lock() {
-            miners.push(miner.clone());
-        }
+    pub fn register_miner(&self, miner: &Sender<SignedTransaction>) -> Result<(), Error> {
+        self.known_miners.lock().map_err(NetworkError::from)?.push(miner.clone());
+        Ok(())
+    }
+
+    /// Broadcast transaction to miners.
+    /// Every reachable miner receives it, even if some others are disconnected.
+    pub fn broadcast(&self, transaction: &SignedTransaction) -> Result<(), Error> {
+        send_all(&self.known_miners.lock().map_err(NetworkError::from)?, transaction)?;
+        Ok(())
     }
+}
 
-    /// Broadcast transaction to miners
-    pub fn broadcast(&self, transaction: &SignedTransaction) {
-        if let Ok(mut miners) = self.known_miners.lock() {
-            for miner in miners.iter_mut() {
-                miner.send(transaction.clone()).unwrap();
+/// Add a block to the local chain and drop its transactions from the mempool if the chain changed.
+/// After a reorganization, the transactions of the rolled back blocks are pending again.
+/// The tip is compared instead of the status, since orphans connected by the block can change
+/// the active chain even if the block itself only lands on a side branch.
+fn add_block(bc: &mut Blockchain, mempool: &Shared<Mempool>, block: Block) -> Result<BlockStatus, BlockError> {
+    let previous_tip = bc.tip_hash();
+    let status = bc.add(block.clone());
+    if bc.tip_hash() != previous_tip {
+        if let Ok(mut pool) = mempool.lock() {
+            let rolled_back: Vec<SignedTransaction> = bc.off_chain(&previous_tip)
+                .iter()
+                .flat_map(|b| b.transactions.leaves().into_iter().filter(|st| !st.transaction.is_coinbase()))
+                .cloned()
+                .collect();
+            // Transactions that are part of the new branch as well are rejected by the ledger
+            for st in rolled_back {
+                let _ = pool.insert(st, bc.ledger());
             }
+            pool.remove_block(&block);
+            pool.prune(bc.ledger());
         }
     }
+    status
 }
 
+/// Move `received` and all transactions waiting in the channel into the mempool.
+/// Returns whether there were any.
+fn receive_transactions(received: Vec<SignedTransaction>, receiver: &STReceiver, bc: &Blockchain, pool: &mut Mempool) -> bool {
+    let mut any = false;
+    for t in received.into_iter().chain(receiver.try_iter()) {
+        match pool.insert(t, bc.ledger()) {
+            Ok(()) => info!("Received a new, valid transaction"),
+            Err(e) => warn!("Rejected transaction: {}", e),
+        }
+        any = true;
+    }
+    any
+}
+
+/// Send a copy of `item` to every peer, counting the ones that are disconnected
+fn send_all<T: Clone>(peers: &[Sender<T>], item: &T) -> Result<(), NetworkError> {
+    let failed = peers.iter().filter(|peer| peer.send(item.clone()).is_err()).count();
+    if failed > 0 {
+        return Err(NetworkError::Disconnected{ peers: failed });
+    }
+    Ok(())
+}
+
+fn sign_transaction(private_key: &RSAPrivateKey, t: Transaction) -> SignedTransaction {
+    let hashed = t.hash();
+    let padding = PaddingScheme::new_pkcs1v15_sign(Some(Hash::SHA2_256));
+    let s = private_key.sign(padding, &hashed).expect("A SHA-256 digest always fits into the key");
+
+    SignedTransaction{
+        transaction: t,
+        signature: s
+    }
+}
+
+#[cfg(test)]
+mod test{
+    // Imports
+    use super::*;
+    use crate::test_utils::{coins, mine, mine_on};
+
+    #[test]
+    fn reinsert_after_reorganization_through_orphans() {
+        let alice = Trader::new();
+        let bob = Trader::new();
+        let config = GenesisConfig{
+            allocations: vec![(alice.public_key.clone(), coins(10))],
+            ..GenesisConfig::default()
+        };
+        let mut bc = Blockchain::from_genesis(&config).unwrap();
+        let mempool = Arc::new(Mutex::new(Mempool::default()));
+        let gen = bc.blocks[0].clone();
+        let payment = alice.sign(Transaction::new(alice.public_key.clone(), bob.public_key.clone(), coins(1)));
+        let a1 = mine(&bc, &alice, vec![payment.clone()]);
+        assert_eq!(add_block(&mut bc, &mempool, a1), Ok(BlockStatus::Extended));
+
+        // Bob's heavier branch arrives tip first, its parent only lands on a side branch itself
+        let b1 = mine_on(&gen, &bc, &bob, Vec::new(), 1);
+        let b2 = mine_on(&b1, &bc, &bob, Vec::new(), 2);
+        assert_eq!(add_block(&mut bc, &mempool, b2.clone()), Ok(BlockStatus::Orphaned));
+        assert_eq!(add_block(&mut bc, &mempool, b1), Ok(BlockStatus::SideBranch));
+        assert_eq!(bc.tip_hash(), b2.hash());
+
+        // The payment of the rolled back block is pending again
+        let pool = mempool.lock().unwrap();
+        assert!(pool.contains(&payment.transaction.hash()));
+        assert_eq!(pool.len(), 1);
+    }
+}
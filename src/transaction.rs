@@ -1,17 +1,83 @@
 use rsa::{PaddingScheme, Hash as HashTypes, PublicKey};
-use super::utils::{sha256_digest, any_as_u8_slice};
+use super::utils::sha256;
+use crate::amount::Amount;
+use crate::encoding::{Encode, Decode, Reader, DecodeError, ENCODING_VERSION, encode_seq, decode_seq};
 use rsa::RSAPublicKey;
-use std::hash::{Hash, Hasher};
-use std::fmt::Debug;
+use std::fmt;
+
+/// Prefix of the signed digest, so a transaction signature can't be reused for other data
+const SIGNING_TAG: &[u8] = b"blockchain.rs/transaction";
+
+/// Fee paid by transactions created through `Transaction::new` (0.1 coins)
+pub const DEFAULT_FEE: Amount = Amount::from_base_units(10_000_000);
+
+/// Why a signed transaction is invalid on its own, regardless of any ledger state
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TxError{
+    /// One of the amounts lies outside of the valid range
+    AmountOutOfRange,
+    /// The sum of all amounts is not a valid amount
+    Overflow,
+    /// The signature doesn't match the transaction and its sender
+    InvalidSignature,
+}
+
+impl fmt::Display for TxError{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TxError::AmountOutOfRange => write!(f, "transaction amount is out of range"),
+            TxError::Overflow => write!(f, "transaction total overflows"),
+            TxError::InvalidSignature => write!(f, "transaction signature is invalid"),
+        }
+    }
+}
+
+impl std::error::Error for TxError {}
+
+/// What a transaction does, besides moving coins from the sender to the receiver
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TxKind{
+    /// A regular payment
+    Transfer,
+    /// The first transaction of every block, creates the block reward and
+    /// collects all fees and tips for the miner
+    Coinbase,
+    /// Locks the amount as stake of the receiver instead of paying it out (proof-of-stake)
+    Stake,
+    /// An allocation of the genesis block, never valid in any other block
+    Genesis,
+}
 
 #[derive(Clone, Debug)]
 pub struct Transaction{
+    pub kind: TxKind,
     pub sender: RSAPublicKey,
     pub receiver: RSAPublicKey,
-    pub amount: f32,
-    pub change: f32,
-    pub fee: f32,
-    pub tip: f32,
+    pub amount: Amount,
+    pub change: Amount,
+    pub fee: Amount,
+    pub tip: Amount,
+    /// Sequence number of the transaction among all transactions of the sender,
+    /// starting at zero. Prevents a signed transaction from being replayed.
+    pub nonce: u64,
+    /// Previous outputs spent by this transaction, only used by the UTXO ledger
+    pub inputs: Vec<OutPoint>,
+    /// Outputs in addition to the receiver and the change
+    pub outputs: Vec<TxOutput>,
+}
+
+/// Reference to a single output of a previous transaction
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct OutPoint{
+    pub tx_hash: Vec<u8>,
+    pub index: u32,
+}
+
+/// Coins paid to a public key
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TxOutput{
+    pub receiver: RSAPublicKey,
+    pub amount: Amount,
 }
 
 #[derive(Clone, Debug)]
@@ -20,42 +86,252 @@ pub struct SignedTransaction{
     pub signature: Vec<u8>,
 }
 
-impl Hash for Transaction{
-    /// TODO: This is bad, since unsafe
-    fn hash<H: Hasher>(&self, state: &mut H){
-        let bytes = unsafe{ any_as_u8_slice(&self.amount) };
-        bytes.hash(state);
+impl Encode for Transaction{
+    fn encode(&self, buf: &mut Vec<u8>){
+        ENCODING_VERSION.encode(buf);
+        self.kind.encode(buf);
+        self.sender.encode(buf);
+        self.receiver.encode(buf);
+        self.amount.encode(buf);
+        self.change.encode(buf);
+        self.fee.encode(buf);
+        self.tip.encode(buf);
+        self.nonce.encode(buf);
+        encode_seq(self.inputs.iter(), buf);
+        encode_seq(self.outputs.iter(), buf);
+    }
+}
+
+impl Decode for Transaction{
+    fn decode(reader: &mut Reader) -> Result<Self, DecodeError> {
+        reader.expect_version()?;
+        Ok(Transaction{
+            kind: TxKind::decode(reader)?,
+            sender: RSAPublicKey::decode(reader)?,
+            receiver: RSAPublicKey::decode(reader)?,
+            amount: Amount::decode(reader)?,
+            change: Amount::decode(reader)?,
+            fee: Amount::decode(reader)?,
+            tip: Amount::decode(reader)?,
+            nonce: u64::decode(reader)?,
+            inputs: decode_seq(reader)?,
+            outputs: decode_seq(reader)?,
+        })
     }
 }
 
-impl Hash for SignedTransaction{
-    fn hash<H: Hasher>(&self, state: &mut H) {
-            self.signature.hash(state);
+impl Encode for TxKind{
+    fn encode(&self, buf: &mut Vec<u8>) {
+        let tag: u8 = match self {
+            TxKind::Transfer => 0,
+            TxKind::Coinbase => 1,
+            TxKind::Stake => 2,
+            TxKind::Genesis => 3,
+        };
+        tag.encode(buf);
+    }
+}
+
+impl Decode for TxKind{
+    fn decode(reader: &mut Reader) -> Result<Self, DecodeError> {
+        match u8::decode(reader)? {
+            0 => Ok(TxKind::Transfer),
+            1 => Ok(TxKind::Coinbase),
+            2 => Ok(TxKind::Stake),
+            3 => Ok(TxKind::Genesis),
+            _ => Err(DecodeError::InvalidValue("transaction kind")),
+        }
+    }
+}
+
+impl Encode for OutPoint{
+    fn encode(&self, buf: &mut Vec<u8>) {
+        self.tx_hash.encode(buf);
+        self.index.encode(buf);
+    }
+}
+
+impl Decode for OutPoint{
+    fn decode(reader: &mut Reader) -> Result<Self, DecodeError> {
+        Ok(OutPoint{
+            tx_hash: Vec::decode(reader)?,
+            index: u32::decode(reader)?,
+        })
+    }
+}
+
+impl Encode for TxOutput{
+    fn encode(&self, buf: &mut Vec<u8>) {
+        self.receiver.encode(buf);
+        self.amount.encode(buf);
+    }
+}
+
+impl Decode for TxOutput{
+    fn decode(reader: &mut Reader) -> Result<Self, DecodeError> {
+        Ok(TxOutput{
+            receiver: RSAPublicKey::decode(reader)?,
+            amount: Amount::decode(reader)?,
+        })
+    }
+}
+
+impl Encode for SignedTransaction{
+    fn encode(&self, buf: &mut Vec<u8>) {
+        self.transaction.encode(buf);
+        self.signature.encode(buf);
+    }
+}
+
+impl Decode for SignedTransaction{
+    fn decode(reader: &mut Reader) -> Result<Self, DecodeError> {
+        Ok(SignedTransaction{
+            transaction: Transaction::decode(reader)?,
+            signature: Vec::decode(reader)?,
+        })
     }
 }
 
 impl Transaction{
-    pub fn new(s: RSAPublicKey, r: RSAPublicKey, amount: f32) -> Transaction{
+    pub fn new(s: RSAPublicKey, r: RSAPublicKey, amount: Amount) -> Transaction{
         Transaction{
+            kind: TxKind::Transfer,
             sender: s,
             receiver: r,
-            amount: amount,
-            change: 0.0,
-            fee: 0.1,
-            tip: 0.0, 
+            amount,
+            change: Amount::ZERO,
+            fee: DEFAULT_FEE,
+            tip: Amount::ZERO,
+            nonce: 0,
+            inputs: Vec::new(),
+            outputs: Vec::new(),
+        }
+    }
+
+    /// Create the coinbase transaction of the block at `height`, paying `amount` to the miner.
+    /// The height is stored as the nonce, which makes every coinbase unique.
+    pub fn coinbase(miner: RSAPublicKey, amount: Amount, height: u64) -> Transaction{
+        Transaction{
+            kind: TxKind::Coinbase,
+            sender: miner.clone(),
+            receiver: miner,
+            amount,
+            change: Amount::ZERO,
+            fee: Amount::ZERO,
+            tip: Amount::ZERO,
+            nonce: height,
+            inputs: Vec::new(),
+            outputs: Vec::new(),
+        }
+    }
+
+    /// Create the genesis allocation of `amount` to `receiver`.
+    /// The index of the allocation is stored as the nonce, which keeps multiple allocations
+    /// to the same key apart.
+    pub fn allocation(receiver: RSAPublicKey, amount: Amount, index: u64) -> Transaction{
+        Transaction{
+            kind: TxKind::Genesis,
+            ..Transaction::coinbase(receiver, amount, index)
         }
     }
 
-    pub fn tip(&mut self, tip: f32) {
+    /// Lock `amount` of the coins of `staker` as their own stake
+    pub fn stake(staker: RSAPublicKey, amount: Amount) -> Transaction{
+        Transaction{
+            kind: TxKind::Stake,
+            ..Transaction::new(staker.clone(), staker, amount)
+        }
+    }
+
+    pub fn is_coinbase(&self) -> bool {
+        self.kind == TxKind::Coinbase
+    }
+
+    pub fn is_stake(&self) -> bool {
+        self.kind == TxKind::Stake
+    }
+
+    pub fn is_allocation(&self) -> bool {
+        self.kind == TxKind::Genesis
+    }
+
+    pub fn tip(&mut self, tip: Amount) {
         self.tip = tip;
     }
+
+    pub fn nonce(&mut self, nonce: u64) {
+        self.nonce = nonce;
+    }
+
+    /// Pay the remainder of the spent inputs back to the sender
+    pub fn change(&mut self, change: Amount) {
+        self.change = change;
+    }
+
+    /// Spend the given outputs of previous transactions
+    pub fn spend(&mut self, inputs: Vec<OutPoint>) {
+        self.inputs = inputs;
+    }
+
+    /// Add another output next to the receiver
+    pub fn pay(&mut self, receiver: RSAPublicKey, amount: Amount) {
+        self.outputs.push(TxOutput{ receiver, amount });
+    }
+
+    /// All outputs created by the transaction, in the order that determines their index:
+    /// the receiver, the change (if any) and then the additional outputs.
+    /// A stake has no receiver output, its amount is locked instead.
+    pub fn all_outputs(&self) -> Vec<TxOutput> {
+        let mut outputs = Vec::new();
+        if !self.is_stake() {
+            outputs.push(TxOutput{ receiver: self.receiver.clone(), amount: self.amount });
+        }
+        if self.change != Amount::ZERO {
+            outputs.push(TxOutput{ receiver: self.sender.clone(), amount: self.change });
+        }
+        outputs.extend(self.outputs.iter().cloned());
+        outputs
+    }
+
+    /// Sum of amount, change, fee, tip and the additional outputs.
+    /// Returns `None` if the sum overflows.
+    pub fn total(&self) -> Option<Amount> {
+        let mut total = self.amount
+            .checked_add(self.change)?
+            .checked_add(self.fee)?
+            .checked_add(self.tip)?;
+        for output in self.outputs.iter() {
+            total = total.checked_add(output.amount)?;
+        }
+        Some(total)
+    }
+
+    /// The digest that gets signed by the sender.
+    /// Commits to every field of the transaction, including both public keys.
+    pub fn hash(&self) -> Vec<u8> {
+        let mut bytes = SIGNING_TAG.to_vec();
+        self.encode(&mut bytes);
+        sha256(&bytes)
+    }
 }
 
 impl SignedTransaction{
-    pub fn is_valid(&self) -> bool{
-        let hashed = sha256_digest(&self.transaction);
+    /// Check the amounts and the signature of the sender
+    pub fn verify(&self) -> Result<(), TxError> {
+        // Reject amounts outside of the valid range, even if they are correctly signed
+        let t = &self.transaction;
+        if ![t.amount, t.change, t.fee, t.tip].iter().all(|a| a.is_valid()) {
+            return Err(TxError::AmountOutOfRange);
+        }
+        if !t.total().is_some_and(Amount::is_valid) {
+            return Err(TxError::Overflow);
+        }
+
+        let hashed = self.transaction.hash();
         let padding = PaddingScheme::new_pkcs1v15_sign(Some(HashTypes::SHA2_256));
-        self.transaction.sender.verify(padding, &hashed, &self.signature).is_ok()
+        self.transaction.sender
+            .verify(padding, &hashed, &self.signature)
+            .map_err(|_| TxError::InvalidSignature)
     }
 }
 
@@ -64,6 +340,11 @@ mod test{
     // Imports
     use super::*;
     use crate::trader::Trader;
+    use crate::test_utils::coins;
+
+    fn plus_one(a: &mut Amount) {
+        *a = a.checked_add(coins(1)).unwrap();
+    }
 
     #[test]
     fn validate_signature(){
@@ -71,13 +352,66 @@ mod test{
         let trader_2 = Trader::new();
 
         // Assert that correct transactions are valid
-        let t = Transaction::new(trader_1.public_key.clone(), trader_2.public_key.clone(), 1.0);
+        let t = Transaction::new(trader_1.public_key.clone(), trader_2.public_key.clone(), coins(1));
         let st_good = trader_1.sign(t);
-        assert!(st_good.is_valid());
+        assert_eq!(st_good.verify(), Ok(()));
 
         // Assert that incorrect transactions are invalid
-        let t_ = Transaction::new(trader_1.public_key.clone(), trader_2.public_key.clone(), 1.0);
+        let t_ = Transaction::new(trader_1.public_key.clone(), trader_2.public_key.clone(), coins(1));
         let st_bad = trader_2.sign(t_);
-        assert!(!st_bad.is_valid());
+        assert_eq!(st_bad.verify(), Err(TxError::InvalidSignature));
+    }
+
+    #[test]
+    fn tampering_invalidates_signature(){
+        let trader_1 = Trader::new();
+        let trader_2 = Trader::new();
+        let mallory = Trader::new();
+
+        let t = Transaction::new(trader_1.public_key.clone(), trader_2.public_key.clone(), coins(1));
+        let st = trader_1.sign(t);
+        assert_eq!(st.verify(), Ok(()));
+
+        let tampered: Vec<fn(&mut Transaction)> = vec![
+            |t| plus_one(&mut t.amount),
+            |t| plus_one(&mut t.change),
+            |t| plus_one(&mut t.fee),
+            |t| plus_one(&mut t.tip),
+            |t| t.receiver = t.sender.clone(),
+            |t| t.nonce += 1,
+            |t| t.kind = TxKind::Coinbase,
+            |t| t.pay(t.sender.clone(), coins(1)),
+            |t| t.spend(vec![OutPoint{ tx_hash: vec![0; 32], index: 0 }]),
+        ];
+        for tamper in tampered {
+            let mut bad = st.clone();
+            tamper(&mut bad.transaction);
+            assert_eq!(bad.verify(), Err(TxError::InvalidSignature));
+        }
+
+        // Redirect the payment to somebody else
+        let mut bad = st.clone();
+        bad.transaction.receiver = mallory.public_key.clone();
+        assert_eq!(bad.verify(), Err(TxError::InvalidSignature));
+
+        // Claim the payment was sent by somebody else
+        let mut bad = st;
+        bad.transaction.sender = trader_2.public_key.clone();
+        assert_eq!(bad.verify(), Err(TxError::InvalidSignature));
+    }
+
+    #[test]
+    fn reject_invalid_amounts(){
+        let trader_1 = Trader::new();
+        let trader_2 = Trader::new();
+
+        // Out of range, even though the signature is correct
+        let t = Transaction::new(trader_1.public_key.clone(), trader_2.public_key.clone(), Amount::from_base_units(u64::MAX));
+        assert_eq!(trader_1.sign(t).verify(), Err(TxError::AmountOutOfRange));
+
+        // Every single amount is valid, but their sum is not
+        let mut t = Transaction::new(trader_1.public_key.clone(), trader_2.public_key.clone(), coins(20_000_000));
+        t.tip(coins(20_000_000));
+        assert_eq!(trader_1.sign(t).verify(), Err(TxError::Overflow));
     }
 }
@@ -1,26 +1,15 @@
 use std::time::SystemTime;
 use sha2::{Digest, Sha256};
-use std::hash::{Hash, Hasher};
-use std::collections::hash_map::DefaultHasher;
-use std::fmt::Debug;
 use std::iter;
 use rand::{Rng, thread_rng};
 use rand::distributions::Alphanumeric;
-
-pub unsafe fn any_as_u8_slice<T: Sized>(p: &T) -> &[u8]{
-    // This is dangerous - do this better
-    ::std::slice::from_raw_parts(
-        (p as *const T) as *const u8,
-        ::std::mem::size_of::<T>(),
-    )
-}
+use crate::encoding::Encode;
 
 /// Generates a random alphanumeric sequence
 pub fn random_id(length: usize) -> String {
     let mut rng = thread_rng();
     iter::repeat(())
         .map(|_| rng.sample(Alphanumeric))
-        .map(char::from)
         .take(length)
         .collect()
 }
@@ -32,11 +21,40 @@ pub fn get_unix_timestamp() -> u64 {
     }
 }
 
-pub fn sha256_digest<T: Hash + Debug>(t: T) -> Vec<u8>{
-    let mut hasher = DefaultHasher::new();
-    t.hash(&mut hasher);
-    let hash = hasher.finish().to_ne_bytes();
+/// SHA-256 of raw bytes
+pub fn sha256(bytes: &[u8]) -> Vec<u8> {
+    Sha256::digest(bytes).to_vec()
+}
+
+/// SHA-256 of the canonical encoding of `t`
+pub fn sha256_digest<T: Encode + ?Sized>(t: &T) -> Vec<u8>{
+    sha256(&t.to_bytes())
+}
+
+/// Formats bytes as lowercase hex
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Parse a hex string, returns `None` if it isn't valid hex
+pub fn from_hex(s: &str) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) || !s.is_ascii() {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|ix| u8::from_str_radix(&s[ix..ix + 2], 16).ok())
+        .collect()
+}
 
-    // Redigest the hash
-    Sha256::digest(&hash).to_vec()
+/// Number of leading zero bits of a hash
+pub fn leading_zero_bits(hash: &[u8]) -> u32 {
+    let mut zeros = 0;
+    for byte in hash {
+        zeros += byte.leading_zeros();
+        if *byte != 0 {
+            break;
+        }
+    }
+    zeros
 }